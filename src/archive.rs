@@ -0,0 +1,201 @@
+//! On-the-fly ZIP archives of the directories sent to mobile devices.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, DeflateOption, ZipDateTime, ZipDateTimeBuilder, ZipEntryBuilder};
+use axum::body::Bytes;
+use futures_util::stream::{self, Stream, StreamExt};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::io::ReaderStream;
+
+use crate::render::utc_date_time;
+use crate::QrSyncResult;
+
+/// Size of the in-memory pipe between the archiver and the HTTP response.
+const ZIP_BUFFER_SIZE: usize = 64 * 1024;
+/// Maximum compression level supported by deflate.
+const MAX_COMPRESSION_LEVEL: u32 = 9;
+
+/// Options controlling how directories are archived when sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZipOptions {
+    compression_level: u32,
+    include_hidden: bool,
+}
+
+impl Default for ZipOptions {
+    fn default() -> Self {
+        ZipOptions {
+            compression_level: 6,
+            include_hidden: false,
+        }
+    }
+}
+
+impl ZipOptions {
+    /// Create a new instance of ZipOptions. The compression level goes from 0 (no compression) to
+    /// 9 (best compression), higher values are capped to 9.
+    pub fn new(compression_level: u32, include_hidden: bool) -> Self {
+        ZipOptions {
+            compression_level: compression_level.min(MAX_COMPRESSION_LEVEL),
+            include_hidden,
+        }
+    }
+
+    /// Build the entry for a file according to the compression level.
+    fn entry(&self, name: String) -> ZipEntryBuilder {
+        if self.compression_level == 0 {
+            ZipEntryBuilder::new(name.into(), Compression::Stored)
+        } else {
+            ZipEntryBuilder::new(name.into(), Compression::Deflate)
+                .deflate_option(DeflateOption::Other(self.compression_level as i32))
+        }
+    }
+}
+
+/// Stream a ZIP archive of `dir` while it is being built, without temporary files and without
+/// buffering the whole archive in memory. If the archive cannot be completed the stream ends with
+/// an error, so the client does not mistake a truncated archive for a complete one.
+pub(crate) fn zip_stream(dir: PathBuf, options: ZipOptions) -> impl Stream<Item = io::Result<Bytes>> + Send {
+    let (reader, writer) = tokio::io::duplex(ZIP_BUFFER_SIZE);
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let result = write_zip(&dir, options, writer).await;
+        if let Err(e) = &result {
+            tracing::error!("Unable to archive directory {}: {}", dir.display(), e);
+        }
+        let _ = tx.send(result);
+    });
+    let outcome = stream::once(rx).filter_map(|result| async move {
+        match result {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(Err(io::Error::other(e.to_string()))),
+            Err(_) => Some(Err(io::Error::other("ZIP archiver stopped"))),
+        }
+    });
+    ReaderStream::new(reader).chain(outcome)
+}
+
+/// Write a ZIP archive with the content of `dir` into `writer`. Entries are stored under a
+/// top-level folder named after the directory, symlinks to directories are not followed.
+async fn write_zip<W: AsyncWrite + Unpin>(dir: &Path, options: ZipOptions, writer: W) -> QrSyncResult<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let root = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "archive".to_string());
+    let mut pending = vec![(dir.to_path_buf(), root)];
+    while let Some((path, name)) = pending.pop() {
+        let metadata = fs::metadata(&path).await?;
+        let entry = ZipEntryBuilder::new(format!("{}/", name).into(), Compression::Stored)
+            .last_modification_date(zip_date_time(metadata.modified()?));
+        zip.write_entry_whole(entry, &[]).await?;
+        let mut children = Vec::new();
+        let mut entries = fs::read_dir(&path).await?;
+        while let Some(child) = entries.next_entry().await? {
+            children.push(child);
+        }
+        children.sort_by_key(|c| c.file_name());
+        let mut subdirs = Vec::new();
+        for child in children {
+            let file_name = child.file_name().to_string_lossy().to_string();
+            if !options.include_hidden && file_name.starts_with('.') {
+                continue;
+            }
+            let child_path = child.path();
+            let child_name = format!("{}/{}", name, file_name);
+            let file_type = child.file_type().await?;
+            if file_type.is_dir() {
+                subdirs.push((child_path, child_name));
+                continue;
+            }
+            // Symlinks are followed only when pointing to regular files.
+            let metadata = match fs::metadata(&child_path).await {
+                Ok(metadata) if metadata.is_file() => metadata,
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::warn!("Skipping {} from the archive: {}", child_path.display(), e);
+                    continue;
+                }
+            };
+            let mut entry = options
+                .entry(child_name)
+                .last_modification_date(zip_date_time(metadata.modified()?));
+            #[cfg(target_family = "unix")]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                entry = entry.unix_permissions(metadata.permissions().mode() as u16);
+            }
+            let f = File::open(&child_path).await?;
+            let mut entry_writer = zip.write_entry_stream(entry).await?;
+            futures_util::io::copy(f.compat(), &mut entry_writer).await?;
+            entry_writer.close().await?;
+        }
+        pending.extend(subdirs.into_iter().rev());
+    }
+    let mut writer = zip.close().await?.into_inner();
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Convert a system time to the MS-DOS date and time format used by ZIP, in UTC.
+fn zip_date_time(time: SystemTime) -> ZipDateTime {
+    let (year, month, day, hour, minute, second) = utc_date_time(time);
+    ZipDateTimeBuilder::new()
+        .year(year)
+        .month(month)
+        .day(day)
+        .hour(hour)
+        .minute(minute)
+        .second(second)
+        .build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_zip_date_time() {
+        let date = zip_date_time(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(
+            (date.year(), date.month(), date.day(), date.hour(), date.minute()),
+            (2023, 11, 14, 22, 13)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zip_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("photos");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        std::fs::write(root.join("sub/b.txt"), "world").unwrap();
+        std::fs::write(root.join(".hidden"), "secret").unwrap();
+        let mut archive = Vec::new();
+        let mut stream = Box::pin(zip_stream(root, ZipOptions::default()));
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk.unwrap());
+        }
+        let reader = async_zip::base::read::mem::ZipFileReader::new(archive).await.unwrap();
+        let names: Vec<String> = reader
+            .file()
+            .entries()
+            .iter()
+            .map(|e| e.filename().as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["photos/", "photos/a.txt", "photos/sub/", "photos/sub/b.txt"]
+        );
+    }
+}
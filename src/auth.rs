@@ -0,0 +1,407 @@
+//! Per-session access control for the HTTP routes.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{Form, Query, State};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use base64::{engine::general_purpose, Engine as _};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::render::{encode_path_segment, escape_html};
+use crate::{QrSyncError, QrSyncResult};
+
+const PIN_HTML: &str = include_str!("templates/pin.html");
+
+/// Number of random bytes in the access token, 256 bits of entropy.
+const TOKEN_BYTES: usize = 32;
+/// Name of the query parameter carrying the access token in the QR code URL.
+pub(crate) const TOKEN_PARAM: &str = "token";
+/// Name of the cookie carrying the access token after the first request.
+const TOKEN_COOKIE: &str = "qrsync_token";
+/// Name of the signed cookie marking a client which entered the correct PIN.
+const PIN_COOKIE: &str = "qrsync_pin";
+/// Path of the PIN challenge page.
+const PIN_PATH: &str = "/pin";
+/// Number of digits of the PIN.
+const PIN_DIGITS: u32 = 6;
+/// Number of wrong PINs accepted before locking the challenge.
+const MAX_PIN_ATTEMPTS: u32 = 5;
+/// Time the PIN challenge stays locked after too many wrong attempts.
+const PIN_LOCKOUT: Duration = Duration::from_secs(300);
+
+/// Access control state shared by the authentication middlewares.
+#[derive(Debug)]
+pub(crate) struct QrSyncAuth {
+    token: String,
+    pin: Option<String>,
+    secure: bool,
+    secret: [u8; 64],
+    attempts: Mutex<PinAttempts>,
+}
+
+/// Wrong PIN attempts and lockout deadline.
+#[derive(Debug, Default)]
+struct PinAttempts {
+    failed: u32,
+    locked_until: Option<Instant>,
+}
+
+impl QrSyncAuth {
+    /// Create a new instance of QrSyncAuth with a random access token and, if requested, a random
+    /// PIN which must be entered on the challenge page. Cookies are marked `Secure` when the
+    /// server is reached over TLS.
+    pub(crate) fn new(pin: bool, secure: bool) -> QrSyncResult<Self> {
+        let mut secret = [0u8; 64];
+        random_bytes(&mut secret)?;
+        let pin = if pin { Some(random_pin()?) } else { None };
+        Ok(QrSyncAuth {
+            token: random_token()?,
+            pin,
+            secure,
+            secret,
+            attempts: Mutex::new(PinAttempts::default()),
+        })
+    }
+
+    /// Access token which must be presented by every request.
+    pub(crate) fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// PIN which must be entered on the challenge page, if enabled.
+    pub(crate) fn pin(&self) -> Option<&str> {
+        self.pin.as_deref()
+    }
+
+    /// Check a token presented by the client in constant time.
+    fn is_valid(&self, token: &str) -> bool {
+        constant_time_eq(token, &self.token)
+    }
+
+    /// Sign a value with the session secret.
+    fn sign(&self, value: &str) -> String {
+        let mut mac = <Hmac<Sha256> as KeyInit>::new(&self.secret.into());
+        mac.update(value.as_bytes());
+        general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Build the signed cookie value marking a client as unlocked.
+    fn unlocked_cookie(&self) -> QrSyncResult<String> {
+        let nonce = random_token()?;
+        let signature = self.sign(&nonce);
+        Ok(session_cookie(PIN_COOKIE, &format!("{nonce}.{signature}"), self.secure))
+    }
+
+    /// Check the signature of the cookie marking a client as unlocked.
+    fn is_unlocked(&self, cookie: &str) -> bool {
+        match cookie.split_once('.') {
+            Some((nonce, signature)) => constant_time_eq(signature, &self.sign(nonce)),
+            None => false,
+        }
+    }
+
+    /// Verify a PIN entered by the client, counting wrong attempts and locking the challenge for a
+    /// while once too many wrong PINs have been entered.
+    fn verify_pin(&self, pin: &str) -> QrSyncResult<bool> {
+        let expected = match self.pin.as_deref() {
+            Some(expected) => expected,
+            None => return Ok(true),
+        };
+        let mut attempts = self
+            .attempts
+            .lock()
+            .map_err(|_| QrSyncError::Error("PIN attempts lock poisoned".into()))?;
+        if let Some(locked_until) = attempts.locked_until {
+            if Instant::now() < locked_until {
+                return Err(QrSyncError::Locked(
+                    "Too many wrong PINs, try again in a few minutes".into(),
+                ));
+            }
+            attempts.locked_until = None;
+            attempts.failed = 0;
+        }
+        if constant_time_eq(pin.trim(), expected) {
+            attempts.failed = 0;
+            return Ok(true);
+        }
+        attempts.failed += 1;
+        tracing::warn!("Wrong PIN entered, attempt {} of {}", attempts.failed, MAX_PIN_ATTEMPTS);
+        if attempts.failed >= MAX_PIN_ATTEMPTS {
+            tracing::warn!("Too many wrong PINs, locking the challenge for {:?}", PIN_LOCKOUT);
+            attempts.locked_until = Some(Instant::now() + PIN_LOCKOUT);
+            return Err(QrSyncError::Locked(
+                "Too many wrong PINs, try again in a few minutes".into(),
+            ));
+        }
+        Ok(false)
+    }
+}
+
+/// Compare two strings in constant time.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Fill a buffer with random bytes from the operating system.
+fn random_bytes(bytes: &mut [u8]) -> QrSyncResult<()> {
+    getrandom::getrandom(bytes).map_err(|e| QrSyncError::Error(format!("Unable to generate random data: {e}")))
+}
+
+/// Generate a random numeric PIN.
+fn random_pin() -> QrSyncResult<String> {
+    let modulo = 10u32.pow(PIN_DIGITS);
+    // Reject values above the largest multiple of modulo to avoid a biased PIN.
+    let limit = u32::MAX - u32::MAX % modulo;
+    loop {
+        let mut bytes = [0u8; 4];
+        random_bytes(&mut bytes)?;
+        let value = u32::from_le_bytes(bytes);
+        if value < limit {
+            return Ok(format!("{:0width$}", value % modulo, width = PIN_DIGITS as usize));
+        }
+    }
+}
+
+/// Generate a random URL-safe token.
+pub(crate) fn random_token() -> QrSyncResult<String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    random_bytes(&mut bytes)?;
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Find the value of a parameter inside a query string.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Find the value of a cookie sent by the client.
+pub(crate) fn cookie<'a, B>(req: &'a Request<B>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Build a session cookie which is not readable by scripts nor sent by other sites. Secure cookies
+/// are only sent over HTTPS.
+pub(crate) fn session_cookie(name: &str, value: &str, secure: bool) -> String {
+    let cookie = format!("{name}={value}; Path=/; HttpOnly; SameSite=Strict");
+    if secure {
+        format!("{cookie}; Secure")
+    } else {
+        cookie
+    }
+}
+
+/// Middleware rejecting requests without the session access token with 403. The token is accepted
+/// from the query string of the QR code URL, then stored in a cookie and removed from the URL with
+/// a redirect, so following requests and links inside pages do not need to carry it.
+pub(crate) async fn require_token<B>(State(auth): State<Arc<QrSyncAuth>>, req: Request<B>, next: Next<B>) -> Response {
+    let query_token = req.uri().query().and_then(|q| query_param(q, TOKEN_PARAM));
+    if let Some(token) = query_token {
+        if !auth.is_valid(token) {
+            tracing::warn!("Request to {} with an invalid access token", req.uri().path());
+            return QrSyncError::Forbidden("Invalid access token".into()).into_response();
+        }
+        let cookie = session_cookie(TOKEN_COOKIE, auth.token(), auth.secure);
+        if req.method() == Method::GET {
+            return ([(header::SET_COOKIE, cookie)], Redirect::to(req.uri().path())).into_response();
+        }
+        let mut response = next.run(req).await;
+        if let Ok(value) = cookie.parse() {
+            response.headers_mut().append(header::SET_COOKIE, value);
+        }
+        return response;
+    }
+    match cookie(&req, TOKEN_COOKIE) {
+        Some(token) if auth.is_valid(token) => next.run(req).await,
+        _ => {
+            tracing::warn!("Request to {} without a valid access token", req.uri().path());
+            QrSyncError::Forbidden("Missing or invalid access token, scan the QR code again".into()).into_response()
+        }
+    }
+}
+
+/// Middleware redirecting clients which did not enter the PIN yet to the challenge page. Only the
+/// challenge page itself is reachable without the signed cookie.
+pub(crate) async fn require_pin<B>(State(auth): State<Arc<QrSyncAuth>>, req: Request<B>, next: Next<B>) -> Response {
+    if auth.pin.is_none() || req.uri().path() == PIN_PATH {
+        return next.run(req).await;
+    }
+    match cookie(&req, PIN_COOKIE) {
+        Some(value) if auth.is_unlocked(value) => next.run(req).await,
+        _ if req.method() == Method::GET => {
+            Redirect::to(&format!("{}?next={}", PIN_PATH, encode_path_segment(req.uri().path()))).into_response()
+        }
+        _ => QrSyncError::Forbidden("Enter the PIN shown in the terminal first".into()).into_response(),
+    }
+}
+
+/// Query of the PIN challenge page.
+#[derive(Debug, Deserialize)]
+pub(crate) struct PinQuery {
+    next: Option<String>,
+}
+
+/// Form submitted from the PIN challenge page.
+#[derive(Debug, Deserialize)]
+pub(crate) struct PinForm {
+    pin: String,
+    next: Option<String>,
+}
+
+/// Only allow redirects to local paths after the PIN challenge. Backslashes and control
+/// characters are refused too, as browsers treat `/\host` like `//host` and strip tabs and
+/// newlines from URLs.
+fn local_path(next: Option<&str>) -> &str {
+    match next {
+        Some(next)
+            if next.starts_with('/')
+                && !next.starts_with("//")
+                && !next.chars().any(|c| c == '\\' || c.is_control()) =>
+        {
+            next
+        }
+        _ => "/",
+    }
+}
+
+/// Render the PIN challenge page.
+fn render_pin(next: &str, message: &str) -> Html<String> {
+    Html(
+        PIN_HTML
+            .replace("###NEXT###", &escape_html(next))
+            .replace("###MESSAGE###", &escape_html(message)),
+    )
+}
+
+/// Serve GET /pin URL with the PIN challenge page.
+pub(crate) async fn get_pin(Query(query): Query<PinQuery>) -> impl IntoResponse {
+    render_pin(local_path(query.next.as_deref()), "")
+}
+
+/// Serve POST /pin URL, setting the signed cookie and redirecting to the requested page when the
+/// PIN is correct.
+pub(crate) async fn post_pin(State(auth): State<Arc<QrSyncAuth>>, Form(form): Form<PinForm>) -> QrSyncResult<Response> {
+    let next = local_path(form.next.as_deref());
+    if auth.verify_pin(&form.pin)? {
+        tracing::info!("Correct PIN entered, client unlocked");
+        let cookie = auth.unlocked_cookie()?;
+        return Ok(([(header::SET_COOKIE, cookie)], Redirect::to(next)).into_response());
+    }
+    Ok((StatusCode::FORBIDDEN, render_pin(next, "Wrong PIN, try again")).into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_random_token() {
+        let token = random_token().unwrap();
+        assert_eq!(token.len(), 43);
+        assert_ne!(token, random_token().unwrap());
+    }
+
+    #[test]
+    fn test_is_valid() {
+        let auth = QrSyncAuth::new(false, false).unwrap();
+        assert!(auth.is_valid(auth.token()));
+        assert!(!auth.is_valid("wrong"));
+        assert!(!auth.is_valid(&"a".repeat(43)));
+    }
+
+    #[test]
+    fn test_query_param_and_cookie() {
+        assert_eq!(query_param("a=1&token=abc", "token"), Some("abc"));
+        assert_eq!(query_param("a=1", "token"), None);
+        let req = Request::builder()
+            .header(header::COOKIE, "foo=bar; qrsync_token=abc")
+            .body(())
+            .unwrap();
+        assert_eq!(cookie(&req, TOKEN_COOKIE), Some("abc"));
+        assert_eq!(cookie(&req, "missing"), None);
+    }
+
+    #[test]
+    fn test_random_pin() {
+        let pin = random_pin().unwrap();
+        assert_eq!(pin.len(), 6);
+        assert!(pin.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_unlocked_cookie() {
+        let auth = QrSyncAuth::new(true, false).unwrap();
+        let cookie = auth.unlocked_cookie().unwrap();
+        let value = cookie.split(';').next().unwrap().trim_start_matches("qrsync_pin=");
+        assert!(auth.is_unlocked(value));
+        assert!(!auth.is_unlocked("nonce.forged"));
+        assert!(!QrSyncAuth::new(true, false).unwrap().is_unlocked(value));
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        assert_eq!(
+            session_cookie("name", "value", false),
+            "name=value; Path=/; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(
+            session_cookie("name", "value", true),
+            "name=value; Path=/; HttpOnly; SameSite=Strict; Secure"
+        );
+        let auth = QrSyncAuth::new(true, true).unwrap();
+        assert!(auth.unlocked_cookie().unwrap().ends_with("; Secure"));
+    }
+
+    #[test]
+    fn test_verify_pin_lockout() {
+        let auth = QrSyncAuth::new(true, false).unwrap();
+        let pin = auth.pin().unwrap().to_string();
+        assert!(auth.verify_pin(&pin).unwrap());
+        for _ in 0..MAX_PIN_ATTEMPTS - 1 {
+            assert!(!auth.verify_pin("wrong").unwrap());
+        }
+        assert!(auth.verify_pin("wrong").is_err());
+        assert!(auth.verify_pin(&pin).is_err());
+    }
+
+    #[test]
+    fn test_local_path() {
+        assert_eq!(local_path(Some("/receive")), "/receive");
+        assert_eq!(local_path(Some("//evil.com")), "/");
+        assert_eq!(local_path(Some("https://evil.com")), "/");
+        assert_eq!(local_path(None), "/");
+        assert_eq!(local_path(Some("/\\evil.com")), "/");
+        assert_eq!(local_path(Some("/\t/evil.com")), "/");
+        assert_eq!(local_path(Some("/browse/a\\b")), "/");
+    }
+
+    #[test]
+    fn test_local_path_decoded_query() {
+        let next = |uri: &str| {
+            let Query(query) = Query::<PinQuery>::try_from_uri(&uri.parse().unwrap()).unwrap();
+            local_path(query.next.as_deref()).to_string()
+        };
+        assert_eq!(next("/pin?next=%2Freceive"), "/receive");
+        assert_eq!(next("/pin?next=/%5Cevil.com"), "/");
+        assert_eq!(next("/pin?next=%2F%5Cevil.com"), "/");
+        assert_eq!(next("/pin?next=%2F%2Fevil.com"), "/");
+        assert_eq!(next("/pin?next=/%09/evil.com"), "/");
+    }
+}
@@ -0,0 +1,245 @@
+//! Handling of name collisions for files received from mobile devices.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tokio::fs;
+
+use crate::{QrSyncError, QrSyncResult};
+
+/// Maximum number of alternative names tried when renaming a received file.
+const MAX_RENAME_ATTEMPTS: usize = 10000;
+
+/// Policy applied when a received file already exists inside the root directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Store the file with a new name, like `name (1).jpg`.
+    Rename,
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file and discard the received one.
+    Skip,
+    /// Keep the existing file and report an error.
+    Fail,
+}
+
+impl FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rename" => Ok(OnConflict::Rename),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "skip" => Ok(OnConflict::Skip),
+            "fail" => Ok(OnConflict::Fail),
+            _ => Err(format!(
+                "invalid conflict policy {s:?}, expected one of rename, overwrite, skip, fail"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = match self {
+            OnConflict::Rename => "rename",
+            OnConflict::Overwrite => "overwrite",
+            OnConflict::Skip => "skip",
+            OnConflict::Fail => "fail",
+        };
+        write!(f, "{policy}")
+    }
+}
+
+impl OnConflict {
+    /// Check whether a file can be received before its content is transferred, so that files
+    /// which are going to be skipped or rejected are not written at all. Returns `false` when the
+    /// file must be skipped.
+    pub(crate) async fn precheck(&self, root_dir: &Path, file_name: &str) -> QrSyncResult<bool> {
+        if !fs::try_exists(root_dir.join(file_name)).await? {
+            return Ok(true);
+        }
+        match self {
+            OnConflict::Skip => {
+                tracing::warn!("File {} already exists, skipping it", file_name);
+                Ok(false)
+            }
+            OnConflict::Fail => Err(QrSyncError::AlreadyExists(file_name.to_string())),
+            _ => Ok(true),
+        }
+    }
+
+    /// Move the fully written temporary file `tmp` to `file_name` inside `root_dir` according to
+    /// the policy, returning the final path. `None` is returned when the file has been skipped.
+    ///
+    /// The destination is created with a hard link or a no-replace rename, so concurrent uploads of
+    /// the same name never clobber each other.
+    pub(crate) async fn persist(&self, tmp: &Path, root_dir: &Path, file_name: &str) -> QrSyncResult<Option<PathBuf>> {
+        let path = root_dir.join(file_name);
+        if *self == OnConflict::Overwrite {
+            fs::rename(tmp, &path).await?;
+            return Ok(Some(path));
+        }
+        match link_new(tmp, &path).await {
+            Ok(_) => return Ok(Some(path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+        match self {
+            OnConflict::Skip => {
+                tracing::warn!("File {} already exists, skipping it", path.display());
+                Ok(None)
+            }
+            OnConflict::Fail => Err(QrSyncError::AlreadyExists(file_name.to_string())),
+            _ => {
+                for attempt in 1..=MAX_RENAME_ATTEMPTS {
+                    let path = root_dir.join(renamed(file_name, attempt));
+                    match link_new(tmp, &path).await {
+                        Ok(_) => {
+                            tracing::info!("File {} already exists, storing it as {}", file_name, path.display());
+                            return Ok(Some(path));
+                        }
+                        Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(QrSyncError::Error(format!(
+                    "Unable to find a free name for file {}",
+                    file_name
+                )))
+            }
+        }
+    }
+}
+
+/// Atomically make `tmp` available as `dst`, failing with `AlreadyExists` if `dst` exists. Hard
+/// links are used when the filesystem supports them, otherwise `tmp` is renamed without replacing
+/// an existing `dst`. The final name never exists with partial content.
+async fn link_new(tmp: &Path, dst: &Path) -> io::Result<()> {
+    match fs::hard_link(tmp, dst).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(e),
+        Err(e) => {
+            tracing::debug!("Unable to hard link {} to {}: {}", tmp.display(), dst.display(), e);
+            rename_noreplace(tmp, dst).await
+        }
+    }
+}
+
+/// Rename `tmp` to `dst` with `renameat2(RENAME_NOREPLACE)`, failing with `AlreadyExists` if
+/// `dst` exists. Filesystems without support for the flag fall back to [`rename_checked`].
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+async fn rename_noreplace(tmp: &Path, dst: &Path) -> io::Result<()> {
+    use nix::errno::Errno;
+    use nix::fcntl::{renameat2, RenameFlags};
+
+    let (from, to) = (tmp.to_path_buf(), dst.to_path_buf());
+    let result =
+        tokio::task::spawn_blocking(move || renameat2(None, &from, None, &to, RenameFlags::RENAME_NOREPLACE)).await?;
+    match result {
+        Ok(_) => Ok(()),
+        Err(Errno::EINVAL) | Err(Errno::ENOSYS) => rename_checked(tmp, dst).await,
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Without `renameat2` the existence check and the rename are two steps.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+async fn rename_noreplace(tmp: &Path, dst: &Path) -> io::Result<()> {
+    rename_checked(tmp, dst).await
+}
+
+/// Rename `tmp` to `dst` unless `dst` exists. This is not atomic: a file created with the same
+/// name between the check and the rename is replaced. It is only used on platforms and
+/// filesystems supporting neither hard links nor no-replace renames.
+async fn rename_checked(tmp: &Path, dst: &Path) -> io::Result<()> {
+    if fs::try_exists(dst).await? {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", dst.display()),
+        ));
+    }
+    fs::rename(tmp, dst).await
+}
+
+/// Build the alternative name for a file, inserting the attempt number before the extension.
+fn renamed(file_name: &str, attempt: usize) -> String {
+    match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem} ({attempt}).{ext}"),
+        _ => format!("{file_name} ({attempt})"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_renamed() {
+        assert_eq!(renamed("IMG_0001.jpg", 1), "IMG_0001 (1).jpg");
+        assert_eq!(renamed("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(renamed("README", 3), "README (3)");
+        assert_eq!(renamed(".bashrc", 1), ".bashrc (1)");
+    }
+
+    #[test]
+    fn test_on_conflict_from_str() {
+        assert_eq!("rename".parse::<OnConflict>().unwrap(), OnConflict::Rename);
+        assert_eq!("overwrite".parse::<OnConflict>().unwrap(), OnConflict::Overwrite);
+        assert_eq!("skip".parse::<OnConflict>().unwrap(), OnConflict::Skip);
+        assert_eq!("fail".parse::<OnConflict>().unwrap(), OnConflict::Fail);
+        assert!("whatever".parse::<OnConflict>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_persist_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = dir.path().join("tmp");
+        std::fs::write(&tmp, "first").unwrap();
+        let path = OnConflict::Rename.persist(&tmp, dir.path(), "a.txt").await.unwrap();
+        assert_eq!(path.unwrap(), dir.path().join("a.txt"));
+        let path = OnConflict::Rename.persist(&tmp, dir.path(), "a.txt").await.unwrap();
+        assert_eq!(path.unwrap(), dir.path().join("a (1).txt"));
+        assert!(OnConflict::Skip
+            .persist(&tmp, dir.path(), "a.txt")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(OnConflict::Fail.persist(&tmp, dir.path(), "a.txt").await.is_err());
+        std::fs::remove_file(&tmp).unwrap();
+        std::fs::write(&tmp, "second").unwrap();
+        let path = OnConflict::Overwrite.persist(&tmp, dir.path(), "a.txt").await.unwrap();
+        assert_eq!(path.unwrap(), dir.path().join("a.txt"));
+        assert_eq!(std::fs::read_to_string(dir.path().join("a.txt")).unwrap(), "second");
+        assert_eq!(std::fs::read_to_string(dir.path().join("a (1).txt")).unwrap(), "first");
+    }
+
+    #[tokio::test]
+    async fn test_rename_noreplace() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tmp, dst) = (dir.path().join("tmp"), dir.path().join("dst"));
+        std::fs::write(&tmp, "new").unwrap();
+        std::fs::write(&dst, "old").unwrap();
+        let e = rename_noreplace(&tmp, &dst).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "old");
+        std::fs::remove_file(&dst).unwrap();
+        rename_noreplace(&tmp, &dst).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new");
+        assert!(!tmp.exists());
+    }
+
+    #[tokio::test]
+    async fn test_precheck_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "").unwrap();
+        assert!(OnConflict::Rename.precheck(dir.path(), "a.txt").await.unwrap());
+        assert!(OnConflict::Overwrite.precheck(dir.path(), "a.txt").await.unwrap());
+        assert!(!OnConflict::Skip.precheck(dir.path(), "a.txt").await.unwrap());
+        assert!(OnConflict::Fail.precheck(dir.path(), "a.txt").await.is_err());
+        assert!(OnConflict::Fail.precheck(dir.path(), "b.txt").await.unwrap());
+    }
+}
@@ -1,19 +1,23 @@
 //! Definition and implementation of QrSync and dependencies errors.
 
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind};
 use std::net::AddrParseError;
 use std::str::Utf8Error;
 
+use async_zip::error::ZipError;
 use axum::extract::multipart::MultipartError;
 use axum::http::StatusCode;
-use axum::response::{IntoResponse, Response};
+use axum::response::{Html, IntoResponse, Response};
 use base64::DecodeError;
 use ctrlc::Error as CtrlcError;
 use hyper::Error as HyperError;
 use qr2term::QrError;
 use thiserror::Error;
 
+use crate::render::escape_html;
+
 const ERROR_HTML: &str = include_str!("templates/error-custom.html");
+const EXPIRED_HTML: &str = include_str!("templates/expired.html");
 
 /// Generic QrSync error structure, implementing all error types coming from dependencies.
 #[derive(Error, Debug)]
@@ -42,14 +46,115 @@ pub enum QrSyncError {
     /// Hyper server error.
     #[error("Hyper server error: {0}")]
     Hyper(#[from] HyperError),
+    /// Requested resource not found.
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// Access to the requested resource is forbidden.
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    /// Malformed request.
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    /// Request body exceeding the allowed size.
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    /// Request not supported by the mode QrSync is running in.
+    #[error("Wrong mode: {0}")]
+    WrongMode(String),
+    /// Too many failed attempts, access temporarily locked.
+    #[error("Locked: {0}")]
+    Locked(String),
+    /// Session expired after the idle timeout or the hard expiry deadline.
+    #[error("Session expired")]
+    Expired,
+    /// Received file already existing in the root directory.
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    /// Invalid file name received from the client.
+    #[error("Invalid file name: {0}")]
+    InvalidFileName(String),
+    /// TLS certificate error.
+    #[error("TLS error: {0}")]
+    Tls(String),
+    /// ZIP archive error.
+    #[error("ZIP archive error: {0}")]
+    Zip(#[from] ZipError),
     /// Multipart form error.
     #[error("Multipart form error: {0}")]
-    Multipart(#[from] MultipartError),
+    Multipart(MultipartError),
+}
+
+impl From<MultipartError> for QrSyncError {
+    fn from(e: MultipartError) -> Self {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            QrSyncError::PayloadTooLarge(e.body_text())
+        } else {
+            QrSyncError::Multipart(e)
+        }
+    }
+}
+
+impl QrSyncError {
+    /// HTTP status code returned to the client for this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            QrSyncError::NotFound(_) => StatusCode::NOT_FOUND,
+            QrSyncError::Forbidden(_) => StatusCode::FORBIDDEN,
+            QrSyncError::BadRequest(_)
+            | QrSyncError::InvalidFileName(_)
+            | QrSyncError::Base64(_)
+            | QrSyncError::Utf8(_) => StatusCode::BAD_REQUEST,
+            QrSyncError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            QrSyncError::Locked(_) => StatusCode::TOO_MANY_REQUESTS,
+            QrSyncError::Expired => StatusCode::GONE,
+            QrSyncError::WrongMode(_) | QrSyncError::AlreadyExists(_) => StatusCode::CONFLICT,
+            QrSyncError::Multipart(e) => e.status(),
+            QrSyncError::Io(e) => match e.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for QrSyncError {
     fn into_response(self) -> Response {
-        let body = ERROR_HTML.replace("###ERRORMESSAGE###", &self.to_string());
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        let body = match self {
+            QrSyncError::Expired => EXPIRED_HTML.to_string(),
+            _ => ERROR_HTML.replace("###ERRORMESSAGE###", &escape_html(&self.to_string())),
+        };
+        (self.status_code(), Html(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(QrSyncError::NotFound("".into()).status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(QrSyncError::Forbidden("".into()).status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            QrSyncError::BadRequest("".into()).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            QrSyncError::PayloadTooLarge("".into()).status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(QrSyncError::WrongMode("".into()).status_code(), StatusCode::CONFLICT);
+        assert_eq!(QrSyncError::Expired.status_code(), StatusCode::GONE);
+        assert_eq!(
+            QrSyncError::Io(IoError::from(ErrorKind::NotFound)).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            QrSyncError::Error("".into()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
     }
 }
@@ -1,21 +1,42 @@
 //! QR code and HTTP worker handling.
 
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
+use axum::http::Uri;
 use axum::routing::{get, post};
-use axum::{extract::DefaultBodyLimit, Router};
+use axum::{extract::DefaultBodyLimit, middleware, Router};
+use axum_server::Handle;
 use base64::{engine::general_purpose, Engine as _};
-#[cfg(target_family = "unix")]
-use pnet::datalink;
+use futures_util::future::{self, Either, Future};
 use qr2term::matrix::Matrix;
 use qr2term::qr::Qr;
-use qr2term::render::{Color, QrDark, QrLight, Renderer};
+use qr2term::render::Color;
+use tower_http::catch_panic::CatchPanicLayer;
 use tower_http::limit::RequestBodyLimitLayer;
 
+use crate::auth::{get_pin, post_pin, require_pin, require_token, QrSyncAuth, TOKEN_PARAM};
+#[cfg(target_family = "unix")]
+use crate::interface::{find_address, find_all_addresses};
+use crate::listener::{advertised_ip, bind_listener, bind_listeners, unspecified_address, PortRange};
+use crate::qr::{numbered_path, save_png, save_svg, QrImageOptions};
 use crate::routes::*;
-use crate::{QrSyncError, QrSyncResult};
+use crate::session::{get_session, track_session, Session};
+use crate::shutdown::ShutdownHandle;
+use crate::terminal::{print_qr_code as print_terminal_qr_code, QrRender};
+use crate::transfer::Transfers;
+use crate::{cleanup_partial_files, OnConflict, QrSyncError, QrSyncResult, TlsCertificate, ZipOptions};
+
+/// Time given to the active transfers to finish once a graceful shutdown has been requested.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time given to the connections to stop once the active transfers have been interrupted.
+const CONNECTIONS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval between two checks of the connections still open during a forced shutdown.
+const CONNECTIONS_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Main structure implementing the workflow if sending and receving files between devices.
 /// It fetches the main IP address, generates the QR code, configures and runs the Rocket worker.
@@ -23,10 +44,29 @@ use crate::{QrSyncError, QrSyncResult};
 pub struct QrSyncHttp {
     ip_address: Option<String>,
     port: u16,
-    filename: Option<String>,
+    port_range: Option<PortRange>,
+    filenames: Vec<String>,
     root_dir: PathBuf,
     light_term: bool,
+    terminal_qr: bool,
+    qr_render: QrRender,
+    qr_png: Option<PathBuf>,
+    qr_svg: Option<PathBuf>,
+    qr_image_options: QrImageOptions,
     ipv6: bool,
+    interface: Option<String>,
+    all_addresses: bool,
+    bind: Option<SocketAddr>,
+    public_url: Option<Uri>,
+    on_conflict: OnConflict,
+    zip_options: ZipOptions,
+    browse: bool,
+    pin: bool,
+    tls: Option<TlsCertificate>,
+    transfers: Arc<Transfers>,
+    idle_timeout: Option<Duration>,
+    expires_in: Option<Duration>,
+    shutdown: ShutdownHandle,
 }
 
 impl QrSyncHttp {
@@ -42,53 +82,204 @@ impl QrSyncHttp {
         QrSyncHttp {
             ip_address,
             port,
-            filename,
+            port_range: None,
+            filenames: filename.into_iter().collect(),
             root_dir,
             light_term,
+            terminal_qr: true,
+            qr_render: QrRender::default(),
+            qr_png: None,
+            qr_svg: None,
+            qr_image_options: QrImageOptions::default(),
             ipv6,
+            interface: None,
+            all_addresses: false,
+            bind: None,
+            public_url: None,
+            on_conflict: OnConflict::Rename,
+            zip_options: ZipOptions::default(),
+            browse: false,
+            pin: false,
+            tls: None,
+            transfers: Arc::new(Transfers::new(None)),
+            idle_timeout: None,
+            expires_in: None,
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// Advertise the address of the network interface with the given name instead of the one
+    /// selected automatically.
+    pub fn with_interface(mut self, interface: Option<String>) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// Listen on all the usable addresses instead of a single one, printing a QR code for each of
+    /// them, so the mobile device can scan the one of the network it is connected to.
+    pub fn with_all_addresses(mut self, all_addresses: bool) -> Self {
+        self.all_addresses = all_addresses;
+        self
+    }
+
+    /// Listen on the given address instead of the advertised one, for example on all the
+    /// interfaces inside a container.
+    pub fn with_bind(mut self, bind: Option<SocketAddr>) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// Advertise the given base URL in the QR code instead of the address QrSync listens on, for
+    /// example behind NAT or port forwarding. Only the scheme and the authority can be set.
+    pub fn with_public_url(mut self, public_url: Option<Uri>) -> Self {
+        self.public_url = public_url;
+        self
+    }
+
+    /// Serve several files in send mode, with an index page listing them. This replaces the file
+    /// given to [`QrSyncHttp::new`].
+    pub fn with_filenames(mut self, filenames: Vec<String>) -> Self {
+        self.filenames = filenames;
+        self
+    }
+
+    /// Listen on the first free port of the range instead of the given port. The port actually
+    /// bound is the one encoded in the QR code.
+    pub fn with_port_range(mut self, port_range: Option<PortRange>) -> Self {
+        self.port_range = port_range;
+        self
+    }
+
+    /// Print the QR code in the terminal, enabled by default. It can be disabled when the QR code
+    /// is only saved to image files.
+    pub fn with_terminal_qr(mut self, terminal_qr: bool) -> Self {
+        self.terminal_qr = terminal_qr;
+        self
+    }
+
+    /// Set how the QR code is drawn in the terminal.
+    pub fn with_qr_render(mut self, qr_render: QrRender) -> Self {
+        self.qr_render = qr_render;
+        self
+    }
+
+    /// Save the QR code as a PNG image to the given path. With multiple addresses, the images are
+    /// numbered like `qr-1.png`.
+    pub fn with_qr_png(mut self, qr_png: Option<PathBuf>) -> Self {
+        self.qr_png = qr_png;
+        self
+    }
+
+    /// Save the QR code as an SVG image to the given path. With multiple addresses, the images are
+    /// numbered like `qr-1.svg`.
+    pub fn with_qr_svg(mut self, qr_svg: Option<PathBuf>) -> Self {
+        self.qr_svg = qr_svg;
+        self
+    }
+
+    /// Set the module size and the quiet zone of the QR code images.
+    pub fn with_qr_image_options(mut self, qr_image_options: QrImageOptions) -> Self {
+        self.qr_image_options = qr_image_options;
+        self
+    }
+
+    /// Set the policy applied when a received file already exists inside the root directory.
+    pub fn with_on_conflict(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// Set the options used to archive directories sent to the mobile device.
+    pub fn with_zip_options(mut self, zip_options: ZipOptions) -> Self {
+        self.zip_options = zip_options;
+        self
+    }
+
+    /// Expose the root directory read-only as a browsable file tree instead of receiving files.
+    pub fn with_browse(mut self, browse: bool) -> Self {
+        self.browse = browse;
+        self
+    }
+
+    /// Require the mobile device to enter a PIN, shown only in the terminal, before reaching the
+    /// send, receive and browse pages.
+    pub fn with_pin(mut self, pin: bool) -> Self {
+        self.pin = pin;
+        self
+    }
+
+    /// Serve HTTPS instead of plain HTTP, using the given certificate.
+    pub fn with_tls(mut self, tls: Option<TlsCertificate>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Stop the server once `limit` transfers are completed: downloads in send and browse mode,
+    /// upload batches in receive mode. Without limit the server runs until interrupted.
+    pub fn with_transfer_limit(mut self, limit: Option<usize>) -> Self {
+        self.transfers = Arc::new(Transfers::new(limit));
+        self
+    }
+
+    /// Transfers completed by the server, which can be inspected while and after it runs.
+    pub fn transfers(&self) -> Arc<Transfers> {
+        self.transfers.clone()
+    }
+
+    /// Stop the server once no request has been served for the given time.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Stop the server once the given time has passed since it started, even if it is in use.
+    pub fn with_expires_in(mut self, expires_in: Option<Duration>) -> Self {
+        self.expires_in = expires_in;
+        self
+    }
+
+    /// Handle used to shut the server down, gracefully the first time and forcibly afterwards.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// URL scheme of the QR code URL.
+    fn scheme(&self) -> &str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
         }
     }
 
-    /// Find the public IP by looping over all the available interfaces and finding a public
-    /// routable interface with an IP address which can be reached from the outside.
+    /// Find the public IP, either on the requested interface or on a public routable interface
+    /// with an IP address which can be reached from the outside.
     /// This method currently works only on *nix.
     #[cfg(target_family = "unix")]
     fn find_public_ip(&self) -> QrSyncResult<String> {
-        if self.ip_address.is_some() {
-            return Ok(self.ip_address.as_ref().unwrap().to_string());
+        if let Some(ip_address) = &self.ip_address {
+            return Ok(ip_address.to_string());
         }
-        let all_interfaces = datalink::interfaces();
-        let default_interface = all_interfaces
-            .iter()
-            .find(|e| e.is_up() && !e.is_loopback() && !e.ips.is_empty());
-
-        let mut ip_address = "127.0.0.1".parse()?;
-        match default_interface {
-            Some(interface) => {
-                for ip in interface.ips.iter() {
-                    if self.ipv6 {
-                        if ip.is_ipv6() {
-                            ip_address = ip.ip();
-                            break;
-                        }
-                    } else if ip.is_ipv4() {
-                        ip_address = ip.ip();
-                        break;
-                    }
-                }
-                if !ip_address.is_loopback() {
-                    tracing::debug!("Found IP address {} for interface {}", ip_address, interface.name);
-                    Ok(ip_address.to_string())
-                } else {
-                    Err(QrSyncError::Error(
-                        "Unable to find a valid IP address to bind with. See --ip-address option to specify the IP address to use".into()
-                    ))
-                }
-            }
-            None => Err(QrSyncError::Error(
-                "Unable to find default interface. See --ip-address option to specify the IP address to use".into(),
-            )),
+        Ok(find_address(self.interface.as_deref(), self.ipv6)?.to_string())
+    }
+
+    /// Find the addresses to listen on and to advertise, labeled with the name of their interface
+    /// when all the usable addresses are requested.
+    #[cfg(target_family = "unix")]
+    fn find_addresses(&self) -> QrSyncResult<Vec<(Option<String>, String)>> {
+        if !self.all_addresses || self.ip_address.is_some() {
+            return Ok(vec![(None, self.find_public_ip()?)]);
         }
+        Ok(find_all_addresses(self.interface.as_deref(), self.ipv6)?
+            .into_iter()
+            .map(|(name, ip)| (Some(name), ip.to_string()))
+            .collect())
+    }
+
+    /// Without interfaces discovery only the address given on the command line is used.
+    #[cfg(target_family = "windows")]
+    fn find_addresses(&self) -> QrSyncResult<Vec<(Option<String>, String)>> {
+        Ok(vec![(None, self.find_public_ip()?)])
     }
 
     /// To have IP address autodiscovery on windows, the pnet crate have many dependencies, so we
@@ -105,81 +296,297 @@ impl QrSyncHttp {
         }
     }
 
-    /// Generates the QR code based on the mode QrSync is started, giving the user a different URL
-    /// in case we are expecting the mobile device to send to receive the file.
-    fn generate_qr_code_url(&self, ip_address: &str) -> QrSyncResult<String> {
-        let url = match self.filename.as_ref() {
-            Some(filename) => {
-                tracing::info!("Send mode enabled for file {}", fs::canonicalize(filename)?.display());
-                format!(
-                    "http://{}:{}/{}",
-                    ip_address,
-                    self.port,
-                    general_purpose::URL_SAFE_NO_PAD.encode(filename)
-                )
-            }
-            None => {
-                tracing::info!(
-                    "Receive mode enabled inside directory {}",
-                    fs::canonicalize(&self.root_dir)?.display()
-                );
-                format!("http://{}:{}/receive", ip_address, self.port)
+    /// Log the mode QrSync is started in, with the served files or directory.
+    fn log_mode(&self) -> QrSyncResult<()> {
+        match self.filenames.as_slice() {
+            [] if self.browse => tracing::info!(
+                "Browse mode enabled for directory {}",
+                fs::canonicalize(&self.root_dir)?.display()
+            ),
+            [] => tracing::info!(
+                "Receive mode enabled inside directory {}",
+                fs::canonicalize(&self.root_dir)?.display()
+            ),
+            filenames => {
+                for filename in filenames {
+                    tracing::info!("Send mode enabled for file {}", fs::canonicalize(filename)?.display());
+                }
             }
+        }
+        Ok(())
+    }
+
+    /// Generates the QR code based on the mode QrSync is started, giving the user a different URL
+    /// in case we are expecting the mobile device to send to receive the file. When multiple files
+    /// are sent, the URL points to the index page listing all of them. The session access token is
+    /// embedded in the URL query.
+    fn generate_qr_code_url(&self, ip_address: &str, port: u16, token: &str) -> QrSyncResult<String> {
+        let base_url = match &self.public_url {
+            Some(url) => self.public_base_url(url)?,
+            None => format!("{}://{}:{}", self.scheme(), url_host(ip_address), port),
+        };
+        let url = match self.filenames.as_slice() {
+            [] if self.browse => format!("{}/browse/", base_url),
+            [] => format!("{}/receive", base_url),
+            [filename] => format!("{}/{}", base_url, general_purpose::URL_SAFE_NO_PAD.encode(filename)),
+            _ => format!("{}/send", base_url),
         };
+        let url = format!("{}?{}={}", url, TOKEN_PARAM, token);
         tracing::info!("Scan this QR code with a QR code reader app to open the URL {}", url);
         Ok(url)
     }
 
-    /// Print the QR code to stdout on the terminal and generates white based QRs on dark terminals
-    /// and black based QRs on light terminals.
-    fn generate_qr_code_matrix(&self, data: &str) -> QrSyncResult<Matrix<Color>> {
-        let mut matrix = Qr::from(data)?.to_matrix();
-        if self.light_term {
-            matrix.surround(2, QrDark);
-        } else {
-            matrix.surround(2, QrLight);
+    /// Base URL advertised in the QR code from the public URL. A plain http URL is refused with
+    /// TLS, since the server would only answer https requests. An https URL without TLS is
+    /// accepted, for a reverse proxy terminating TLS in front of QrSync.
+    fn public_base_url(&self, url: &Uri) -> QrSyncResult<String> {
+        let base_url = public_base_url(url)?;
+        if self.tls.is_some() && url.scheme_str() == Some("http") {
+            return Err(QrSyncError::Error(format!(
+                "Public URL {} uses http but the server only speaks TLS, use an https URL or drop --tls",
+                url
+            )));
         }
-        Ok(matrix)
+        Ok(base_url)
+    }
+
+    /// Generate the QR code matrix, shared by the terminal output and the image files.
+    fn generate_qr_code_matrix(&self, data: &str) -> QrSyncResult<Matrix<Color>> {
+        Ok(Qr::from(data)?.to_matrix())
     }
 
-    fn print_qr_code(&self, ip_address: &str) -> QrSyncResult<()> {
-        let url = self.generate_qr_code_url(ip_address)?;
-        let qr = self.generate_qr_code_matrix(&url)?;
-        Renderer::default().print_stdout(&qr);
+    /// Print the QR code in the terminal and save it to the requested image files. The index
+    /// numbers the image files when a QR code is printed for each of several addresses.
+    fn print_qr_code(&self, ip_address: &str, port: u16, token: &str, index: Option<usize>) -> QrSyncResult<()> {
+        let url = self.generate_qr_code_url(ip_address, port, token)?;
+        let matrix = self.generate_qr_code_matrix(&url)?;
+        if let Some(path) = &self.qr_png {
+            save_png(&matrix, &self.qr_image_options, &numbered_path(path, index))?;
+        }
+        if let Some(path) = &self.qr_svg {
+            save_svg(&matrix, &self.qr_image_options, &numbered_path(path, index))?;
+        }
+        if self.terminal_qr {
+            print_terminal_qr_code(matrix, &url, self.qr_render, self.light_term)?;
+        }
         Ok(())
     }
 
     /// Configure Axum, print the QR code and run the HTTP worker.
     pub async fn run(&self) -> QrSyncResult<()> {
-        let state = Arc::new(QrSyncState::new(self.filename.clone(), &self.root_dir));
-        let app = Router::new()
-            .route("/", get(slash))
-            .route("/receive", get(get_receive))
-            .route("/receive_done", get(get_receive_done))
-            .route("/error", get(get_error))
-            .route("/static/bootstrap.min.css", get(static_bootstrap_css))
-            .route("/static/bootstrap.min.css.map", get(static_bootstrap_css_map))
-            .route("/favicon.ico", get(static_favicon))
-            .route("/:file_name", get(get_send))
-            .route("/receive", post(post_receive))
-            .fallback(bad_request)
-            .with_state(state)
-            .layer(DefaultBodyLimit::disable())
-            .layer(RequestBodyLimitLayer::new(250 * 1024 * 1024 * 1024 /* 250Gb */));
-        let ip_address = self.find_public_ip()?;
-        self.print_qr_code(&ip_address)?;
-        let address = format!("{}:{}", ip_address, self.port).parse()?;
-        if let Err(e) = axum::Server::bind(&address).serve(app.into_make_service()).await {
-            tracing::error!("Server error: {e}");
+        if let Some(url) = &self.public_url {
+            self.public_base_url(url)?;
+        }
+        let state = Arc::new(QrSyncState::new(
+            self.filenames.clone(),
+            &self.root_dir,
+            self.on_conflict,
+            self.zip_options,
+            self.browse,
+            self.transfers.clone(),
+        ));
+        let auth = Arc::new(QrSyncAuth::new(self.pin, self.tls.is_some())?);
+        let session = Arc::new(Session::new(self.idle_timeout, self.expires_in));
+        let app = router(state, auth.clone(), session.clone());
+        let addresses = match &self.public_url {
+            Some(url) => vec![(None, public_host(url)?)],
+            None => {
+                let addresses = self.find_addresses()?;
+                for (_, ip_address) in addresses.iter() {
+                    advertised_ip(ip_address)?;
+                }
+                addresses
+            }
+        };
+        let hosts: Vec<String> = addresses.iter().map(|(_, host)| host.clone()).collect();
+        let ports = self.port_range.unwrap_or_else(|| self.port.into());
+        let listeners = match self.bind {
+            Some(bind) => vec![bind_listener(bind)?],
+            None => bind_listeners(&[unspecified_address()], ports)?,
+        };
+        let port = listeners[0].local_addr()?.port();
+        for listener in listeners.iter() {
+            tracing::info!("Listening on {}", listener.local_addr()?);
+        }
+        let tls = match &self.tls {
+            Some(certificate) => Some(certificate.load(&hosts).await?),
+            None => None,
+        };
+        self.log_mode()?;
+        for (i, (label, ip_address)) in addresses.iter().enumerate() {
+            if let Some(label) = label {
+                println!("{} {}", label, ip_address);
+            }
+            let index = if addresses.len() > 1 { Some(i + 1) } else { None };
+            self.print_qr_code(ip_address, port, auth.token(), index)?;
+        }
+        if let Some((_, fingerprint)) = &tls {
+            println!("TLS certificate SHA-256 fingerprint: {}", fingerprint);
+        }
+        if let Some(pin) = auth.pin() {
+            println!("PIN: {}", pin);
+        }
+        tokio::spawn({
+            let session = session.clone();
+            async move { session.report().await }
+        });
+        let mut handles = Vec::with_capacity(listeners.len());
+        let mut servers: Vec<Pin<Box<dyn Future<Output = QrSyncResult<()>> + Send>>> = Vec::new();
+        for listener in listeners {
+            let handle = Handle::new();
+            let app = app.clone().into_make_service();
+            match &tls {
+                Some((config, _)) => {
+                    let server = axum_server::from_tcp_rustls(listener, config.clone())
+                        .handle(handle.clone())
+                        .serve(app);
+                    servers.push(Box::pin(async move { server.await.map_err(QrSyncError::from) }));
+                }
+                None => {
+                    let server = axum_server::from_tcp(listener).handle(handle.clone()).serve(app);
+                    servers.push(Box::pin(async move { server.await.map_err(QrSyncError::from) }));
+                }
+            }
+            handles.push(handle);
+        }
+        let server = future::try_join_all(servers);
+        let server = tokio::spawn(async move { server.await.map(|_| ()) });
+        let transfers = self.transfers.clone();
+        let shutdown = self.shutdown.clone();
+        let requested = future::select(
+            Box::pin(shutdown.graceful()),
+            future::select(Box::pin(transfers.done()), Box::pin(session.expired())),
+        );
+        let result = match future::select(server, requested).await {
+            Either::Left((result, _)) => result,
+            Either::Right((_, server)) => {
+                shutdown.begin_graceful();
+                for handle in handles.iter() {
+                    handle.graceful_shutdown(None);
+                }
+                let forced = future::select(
+                    Box::pin(shutdown.forced()),
+                    Box::pin(tokio::time::sleep(SHUTDOWN_TIMEOUT)),
+                );
+                match future::select(server, forced).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right((_, server)) => {
+                        tracing::warn!("Active transfers interrupted");
+                        for handle in handles.iter() {
+                            handle.shutdown();
+                        }
+                        let result = server.await;
+                        if !connections_closed(&handles, &session).await {
+                            // The process exits right after, so the partial files of the requests
+                            // still running would never be removed by their own drop.
+                            tracing::warn!("Some connections did not stop in time, removing their partial files");
+                            cleanup_partial_files(&self.root_dir);
+                        }
+                        result
+                    }
+                }
+            }
+        };
+        match result {
+            Ok(Err(e)) => tracing::error!("Server error: {e}"),
+            Err(e) => tracing::error!("Server task error: {e}"),
+            Ok(Ok(())) => (),
         }
         Ok(())
     }
 }
 
+/// Axum router serving the pages of the session. The pages require the access token, then the PIN
+/// when enabled, and count as session activity. The static assets and the fallback page are public.
+fn router(state: Arc<QrSyncState>, auth: Arc<QrSyncAuth>, session: Arc<Session>) -> Router {
+    let pin_routes = Router::new()
+        .route("/pin", get(get_pin).post(post_pin))
+        .with_state(auth.clone());
+    let session_routes = Router::new()
+        .route("/session", get(get_session))
+        .with_state(session.clone());
+    Router::new()
+        .route("/", get(slash))
+        .route("/send", get(get_send_index))
+        .route("/browse", get(get_browse_root))
+        .route("/browse/", get(get_browse_root))
+        .route("/browse/*path", get(get_browse))
+        .route("/receive", get(get_receive))
+        .route("/error", get(get_error))
+        .route("/:file_name", get(get_send))
+        .route("/receive", post(post_receive))
+        .merge(pin_routes)
+        .merge(session_routes)
+        .route_layer(middleware::from_fn_with_state(session, track_session))
+        .route_layer(middleware::from_fn_with_state(auth.clone(), require_pin))
+        .route_layer(middleware::from_fn_with_state(auth, require_token))
+        // The static assets are used by the error pages shown to clients without a valid token,
+        // and like the fallback page they do not expose anything about the session.
+        .route("/static/bootstrap.min.css", get(static_bootstrap_css))
+        .route("/static/bootstrap.min.css.map", get(static_bootstrap_css_map))
+        .route("/static/session.js", get(static_session_js))
+        .route("/favicon.ico", get(static_favicon))
+        .fallback(bad_request)
+        .with_state(state)
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(250 * 1024 * 1024 * 1024 /* 250Gb */))
+        .layer(CatchPanicLayer::custom(handle_panic))
+}
+
+/// Wait for the connection tasks and the requests they spawned to end after a forced shutdown,
+/// so interrupted uploads remove their own partial files. Returns `false` if some of them are
+/// still running after [`CONNECTIONS_TIMEOUT`].
+async fn connections_closed(handles: &[Handle], session: &Session) -> bool {
+    let deadline = tokio::time::Instant::now() + CONNECTIONS_TIMEOUT;
+    loop {
+        if handles.iter().all(|h| h.connection_count() == 0) && session.in_flight() == 0 {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(CONNECTIONS_POLL_INTERVAL).await;
+    }
+}
+
+/// Base URL advertised in the QR code from a public URL, without trailing slash. Paths and
+/// queries are refused, since all the routes are served from the root.
+fn public_base_url(url: &Uri) -> QrSyncResult<String> {
+    let invalid = |reason: &str| QrSyncError::Error(format!("Invalid public URL {}: {}", url, reason));
+    let scheme = url.scheme_str().ok_or_else(|| invalid("missing scheme"))?;
+    if scheme != "http" && scheme != "https" {
+        return Err(invalid("the scheme must be http or https"));
+    }
+    let authority = url.authority().ok_or_else(|| invalid("missing host"))?;
+    if !matches!(url.path(), "" | "/") || url.query().is_some() {
+        return Err(invalid("paths and queries are not supported"));
+    }
+    Ok(format!("{}://{}", scheme, authority))
+}
+
+/// Host of a public URL, without the brackets enclosing IPv6 addresses.
+fn public_host(url: &Uri) -> QrSyncResult<String> {
+    public_base_url(url)?;
+    let host = url.host().unwrap_or_default();
+    Ok(host.trim_start_matches('[').trim_end_matches(']').to_string())
+}
+
+/// Host part of a URL for an IP address, with IPv6 addresses enclosed in brackets.
+fn url_host(ip_address: &str) -> String {
+    match ip_address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => ip_address.to_string(),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::terminal::blocks_matrix;
+    use axum::body::Body;
+    use axum::http::{header, Request, StatusCode};
     use pretty_assertions::{assert_eq, assert_ne};
+    use qr2term::render::{QrDark, QrLight};
 
     #[test]
     fn test_find_public_ip_passing_ip_address() {
@@ -220,10 +627,10 @@ mod test {
             false,
             false,
         );
-        let url = http.generate_qr_code_url(ip_address).unwrap();
+        let url = http.generate_qr_code_url(ip_address, 12345, "a-token").unwrap();
         assert_eq!(
             format!(
-                "http://{}:12345/{}",
+                "http://{}:12345/{}?token=a-token",
                 ip_address,
                 general_purpose::URL_SAFE_NO_PAD.encode(file_name)
             ),
@@ -242,8 +649,101 @@ mod test {
             false,
             false,
         );
-        let url = http.generate_qr_code_url(ip_address).unwrap();
-        assert_eq!(format!("http://{}:12345/receive", ip_address,), url);
+        let url = http.generate_qr_code_url(ip_address, 12345, "a-token").unwrap();
+        assert_eq!(format!("http://{}:12345/receive?token=a-token", ip_address,), url);
+    }
+
+    #[test]
+    fn test_generate_qr_code_url_send_mode_multiple_files() {
+        let ip_address = "10.0.0.1";
+        let http = QrSyncHttp::new(
+            Some(ip_address.to_string()),
+            12345,
+            None,
+            PathBuf::from("a-dir"),
+            false,
+            false,
+        )
+        .with_filenames(vec!["a-file".to_string(), "another-file".to_string()]);
+        let url = http.generate_qr_code_url(ip_address, 12345, "a-token").unwrap();
+        assert_eq!(format!("http://{}:12345/send?token=a-token", ip_address), url);
+    }
+
+    #[test]
+    fn test_generate_qr_code_url_browse_mode() {
+        let ip_address = "10.0.0.1";
+        let http = QrSyncHttp::new(
+            Some(ip_address.to_string()),
+            12345,
+            None,
+            PathBuf::from("a-dir"),
+            false,
+            false,
+        )
+        .with_browse(true);
+        let url = http.generate_qr_code_url(ip_address, 12345, "a-token").unwrap();
+        assert_eq!(format!("http://{}:12345/browse/?token=a-token", ip_address), url);
+    }
+
+    #[test]
+    fn test_generate_qr_code_url_tls() {
+        let ip_address = "10.0.0.1";
+        let http = QrSyncHttp::new(
+            Some(ip_address.to_string()),
+            12345,
+            None,
+            PathBuf::from("a-dir"),
+            false,
+            false,
+        )
+        .with_tls(Some(TlsCertificate::SelfSigned));
+        let url = http.generate_qr_code_url(ip_address, 12345, "a-token").unwrap();
+        assert_eq!(format!("https://{}:12345/receive?token=a-token", ip_address), url);
+    }
+
+    #[test]
+    fn test_generate_qr_code_url_ipv6() {
+        let ip_address = "fd00::2";
+        let http = QrSyncHttp::new(
+            Some(ip_address.to_string()),
+            12345,
+            None,
+            PathBuf::from("a-dir"),
+            false,
+            true,
+        );
+        let url = http.generate_qr_code_url(ip_address, 12345, "a-token").unwrap();
+        assert_eq!("http://[fd00::2]:12345/receive?token=a-token", url);
+    }
+
+    #[test]
+    fn test_generate_qr_code_url_public_url() {
+        let http = QrSyncHttp::new(None, 5566, None, PathBuf::from("a-dir"), false, false)
+            .with_public_url(Some("https://myhost.lan:8080/".parse().unwrap()));
+        let url = http.generate_qr_code_url("0.0.0.0", 5566, "a-token").unwrap();
+        assert_eq!("https://myhost.lan:8080/receive?token=a-token", url);
+    }
+
+    #[test]
+    fn test_generate_qr_code_url_public_url_tls() {
+        let http = QrSyncHttp::new(None, 5566, None, PathBuf::from("a-dir"), false, false)
+            .with_tls(Some(TlsCertificate::SelfSigned))
+            .with_public_url(Some("http://myhost.lan:8080".parse().unwrap()));
+        let e = http.generate_qr_code_url("0.0.0.0", 5566, "a-token").unwrap_err();
+        assert!(e.to_string().contains("uses http but the server only speaks TLS"));
+        let http = http.with_public_url(Some("https://myhost.lan:8080".parse().unwrap()));
+        let url = http.generate_qr_code_url("0.0.0.0", 5566, "a-token").unwrap();
+        assert_eq!("https://myhost.lan:8080/receive?token=a-token", url);
+    }
+
+    #[test]
+    fn test_public_url() {
+        let url = "http://[fd00::2]:8080".parse().unwrap();
+        assert_eq!(public_base_url(&url).unwrap(), "http://[fd00::2]:8080");
+        assert_eq!(public_host(&url).unwrap(), "fd00::2");
+        assert!(public_base_url(&"myhost.lan:8080".parse().unwrap()).is_err());
+        assert!(public_base_url(&"ftp://myhost.lan".parse().unwrap()).is_err());
+        assert!(public_base_url(&"http://myhost.lan/prefix".parse().unwrap()).is_err());
     }
 
     #[test]
@@ -257,8 +757,8 @@ mod test {
             false,
             false,
         );
-        let url = http.generate_qr_code_url(ip_address).unwrap();
-        let qr = http.generate_qr_code_matrix(&url).unwrap();
+        let url = format!("http://{}:12345/receive", ip_address);
+        let qr = blocks_matrix(http.generate_qr_code_matrix(&url).unwrap(), http.light_term);
         assert_eq!(qr.pixels().len(), 1089);
         let light_pixels = qr.pixels().iter().filter(|&n| *n == QrLight).count();
         let dark_pixels = qr.pixels().iter().filter(|&n| *n == QrDark).count();
@@ -277,8 +777,8 @@ mod test {
             true,
             false,
         );
-        let url = http.generate_qr_code_url(ip_address).unwrap();
-        let qr = http.generate_qr_code_matrix(&url).unwrap();
+        let url = format!("http://{}:12345/receive", ip_address);
+        let qr = blocks_matrix(http.generate_qr_code_matrix(&url).unwrap(), http.light_term);
         assert_eq!(qr.pixels().len(), 1089);
         let light_pixels = qr.pixels().iter().filter(|&n| *n == QrLight).count();
         let dark_pixels = qr.pixels().iter().filter(|&n| *n == QrDark).count();
@@ -297,6 +797,67 @@ mod test {
             false,
             false,
         );
-        assert_eq!(http.print_qr_code(ip_address).is_ok(), true);
+        assert_eq!(http.print_qr_code(ip_address, 12345, "a-token", None).is_ok(), true);
+    }
+
+    fn test_router(root_dir: &std::path::Path) -> (Router, Arc<QrSyncAuth>) {
+        let state = Arc::new(QrSyncState::new(
+            vec![],
+            root_dir,
+            OnConflict::Rename,
+            ZipOptions::default(),
+            false,
+            Arc::new(Transfers::new(None)),
+        ));
+        let auth = Arc::new(QrSyncAuth::new(false, false).unwrap());
+        let session = Arc::new(Session::new(None, None));
+        (router(state, auth.clone(), session), auth)
+    }
+
+    async fn get(app: &Router, uri: &str, cookie: Option<&str>) -> axum::response::Response {
+        use tower::ServiceExt;
+
+        let mut request = Request::builder().uri(uri);
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router_requires_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, _) = test_router(dir.path());
+        assert_eq!(get(&app, "/receive", None).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            get(&app, "/receive?token=wrong", None).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let response = get(&app, "/receive", Some("qrsync_token=wrong")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_router_token_sets_cookie_and_redirects() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, auth) = test_router(dir.path());
+        let response = get(&app, &format!("/receive?token={}", auth.token()), None).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let headers = response.headers();
+        assert_eq!(headers[header::LOCATION], "/receive");
+        let cookie = headers[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with(&format!("qrsync_token={};", auth.token())));
+        let cookie = cookie.split(';').next().unwrap();
+        assert_eq!(get(&app, "/receive", Some(cookie)).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_router_public_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (app, _) = test_router(dir.path());
+        let response = get(&app, "/static/bootstrap.min.css", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get(&app, "/a/b/c", None).await.status(), StatusCode::IM_A_TEAPOT);
     }
 }
@@ -0,0 +1,421 @@
+//! Network interfaces discovery, used to find the address advertised in the QR code.
+
+use std::net::IpAddr;
+
+use pnet::datalink::{self, NetworkInterface};
+
+use crate::listener::is_link_local;
+use crate::{QrSyncError, QrSyncResult};
+
+/// Name prefixes of virtual interfaces, like container bridges and VPN tunnels, which are rarely
+/// reachable from a mobile device.
+const VIRTUAL_PREFIXES: &[&str] = &[
+    "docker", "br-", "veth", "virbr", "vboxnet", "vmnet", "tun", "tap", "utun", "wg",
+];
+/// Route flag set on usable routes.
+const RTF_UP: u32 = 0x0001;
+/// Route flag set on unreachable routes.
+const RTF_REJECT: u32 = 0x0200;
+
+/// Default route of an interface, with its metric.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DefaultRoute {
+    interface: String,
+    metric: u32,
+}
+
+/// Description of a network interface, as shown by the interfaces listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    /// Name of the interface.
+    pub name: String,
+    /// Addresses of the interface, with their prefix length.
+    pub addresses: Vec<String>,
+    /// Flags of the interface, like UP or LOOPBACK.
+    pub flags: Vec<&'static str>,
+    /// Whether the interface is the one selected when no interface is requested.
+    pub selected: bool,
+}
+
+/// List all the network interfaces, marking the one which would be selected automatically for
+/// the requested address family.
+pub fn interfaces(ipv6: bool) -> Vec<InterfaceInfo> {
+    let all_interfaces = datalink::interfaces();
+    let routes = default_routes(ipv6);
+    let selected = select_interface(&all_interfaces, &routes, ipv6).map(|(i, _)| i.name.clone());
+    all_interfaces
+        .iter()
+        .map(|interface| InterfaceInfo {
+            name: interface.name.clone(),
+            addresses: interface.ips.iter().map(|ip| ip.to_string()).collect(),
+            flags: interface_flags(interface),
+            selected: selected.as_deref() == Some(interface.name.as_str()),
+        })
+        .collect()
+}
+
+/// Find the address to advertise, on the interface with the given name or on the one selected
+/// automatically.
+pub(crate) fn find_address(name: Option<&str>, ipv6: bool) -> QrSyncResult<IpAddr> {
+    let all_interfaces = datalink::interfaces();
+    let (interface, ip_address) = match name {
+        Some(name) => {
+            let interface = all_interfaces.iter().find(|i| i.name == name).ok_or_else(|| {
+                let names: Vec<&str> = all_interfaces.iter().map(|i| i.name.as_str()).collect();
+                QrSyncError::Error(format!(
+                    "Interface {} not found, available interfaces are: {}",
+                    name,
+                    names.join(", ")
+                ))
+            })?;
+            if !interface.is_up() {
+                return Err(QrSyncError::Error(format!("Interface {} is down", name)));
+            }
+            let ip_address = interface_address(interface, ipv6).ok_or_else(|| {
+                QrSyncError::Error(format!(
+                    "Interface {} has no usable {} address",
+                    name,
+                    if ipv6 { "IPv6" } else { "IPv4" }
+                ))
+            })?;
+            (interface, ip_address)
+        }
+        None => select_interface(&all_interfaces, &default_routes(ipv6), ipv6).ok_or_else(|| {
+            QrSyncError::Error(
+                "Unable to find a valid IP address to bind with. See --interface or --ip-address options to specify the address to use".into(),
+            )
+        })?,
+    };
+    tracing::debug!("Found IP address {} for interface {}", ip_address, interface.name);
+    Ok(ip_address)
+}
+
+/// Find all the addresses which can be advertised, labeled with the name of their interface.
+/// Addresses of the preferred family come first, each family ranked as the automatic selection
+/// does. When an interface name is given, only its addresses are returned.
+pub(crate) fn find_all_addresses(name: Option<&str>, ipv6: bool) -> QrSyncResult<Vec<(String, IpAddr)>> {
+    let all_interfaces = datalink::interfaces();
+    let mut addresses = Vec::new();
+    for family_ipv6 in [ipv6, !ipv6] {
+        let routes = default_routes(family_ipv6);
+        for (interface, _) in rank_interfaces(&all_interfaces, &routes, family_ipv6) {
+            if name.is_none_or(|name| interface.name == name) {
+                for ip in interface_addresses(interface, family_ipv6) {
+                    addresses.push((interface.name.clone(), ip));
+                }
+            }
+        }
+    }
+    if addresses.is_empty() {
+        return Err(QrSyncError::Error(
+            "Unable to find any valid IP address to bind with. See --ip-address option to specify the IP address to use".into(),
+        ));
+    }
+    Ok(addresses)
+}
+
+/// Select the interface whose address is advertised, the first one of the ranking.
+fn select_interface<'a>(
+    interfaces: &'a [NetworkInterface],
+    routes: &[DefaultRoute],
+    ipv6: bool,
+) -> Option<(&'a NetworkInterface, IpAddr)> {
+    rank_interfaces(interfaces, routes, ipv6).into_iter().next()
+}
+
+/// Rank the interfaces which are up, are not a loopback and have a usable address of the
+/// requested family. Virtual interfaces come last, then interfaces carrying the default route are
+/// preferred, by route metric, then interfaces with a private address. Ties are broken by the
+/// order of the interfaces.
+fn rank_interfaces<'a>(
+    interfaces: &'a [NetworkInterface],
+    routes: &[DefaultRoute],
+    ipv6: bool,
+) -> Vec<(&'a NetworkInterface, IpAddr)> {
+    let mut ranked: Vec<(&NetworkInterface, IpAddr)> = interfaces
+        .iter()
+        .filter(|i| i.is_up() && !i.is_loopback())
+        .filter_map(|i| interface_address(i, ipv6).map(|ip| (i, ip)))
+        .collect();
+    ranked.sort_by_key(|(i, ip)| {
+        let metric = routes.iter().filter(|r| r.interface == i.name).map(|r| r.metric).min();
+        (is_virtual(&i.name), metric.is_none(), metric, !is_private(ip))
+    });
+    ranked
+}
+
+/// Index of an interface, used as scope identifier of IPv6 link-local addresses.
+pub(crate) fn interface_index(name: &str) -> Option<u32> {
+    datalink::interfaces()
+        .into_iter()
+        .find(|i| i.name == name)
+        .map(|i| i.index)
+}
+
+/// Usable address of the requested family on an interface, preferring private addresses.
+fn interface_address(interface: &NetworkInterface, ipv6: bool) -> Option<IpAddr> {
+    interface_addresses(interface, ipv6).into_iter().next()
+}
+
+/// Usable addresses of the requested family on an interface, private addresses first. IPv6
+/// link-local addresses, which cannot be reached without a zone, are skipped.
+fn interface_addresses(interface: &NetworkInterface, ipv6: bool) -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = interface
+        .ips
+        .iter()
+        .map(|ip| ip.ip())
+        .filter(|ip| ip.is_ipv6() == ipv6)
+        .filter(|ip| !is_link_local(ip))
+        .collect();
+    addresses.sort_by_key(|ip| !is_private(ip));
+    addresses
+}
+
+/// Whether an address belongs to a private range: RFC 1918 for IPv4, unique local addresses for
+/// IPv6.
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xfe00 == 0xfc00,
+    }
+}
+
+/// Whether an interface is a known virtual interface, according to its name.
+fn is_virtual(name: &str) -> bool {
+    VIRTUAL_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Default routes of the requested family, read from the kernel routing table.
+#[cfg(target_os = "linux")]
+fn default_routes(ipv6: bool) -> Vec<DefaultRoute> {
+    let (path, parse): (&str, fn(&str) -> Vec<DefaultRoute>) = if ipv6 {
+        ("/proc/net/ipv6_route", parse_ipv6_routes)
+    } else {
+        ("/proc/net/route", parse_ipv4_routes)
+    };
+    match std::fs::read_to_string(path) {
+        Ok(content) => parse(&content),
+        Err(e) => {
+            tracing::debug!("Unable to read routing table {}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// The kernel routing table is only read on Linux, elsewhere interfaces are ranked without it.
+#[cfg(not(target_os = "linux"))]
+fn default_routes(_ipv6: bool) -> Vec<DefaultRoute> {
+    Vec::new()
+}
+
+/// Parse the default routes from the content of /proc/net/route, where destination, flags, metric
+/// and mask are the 2nd, 4th, 7th and 8th columns. Only the metric is written in decimal.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_ipv4_routes(content: &str) -> Vec<DefaultRoute> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+                return None;
+            }
+            let flags = u32::from_str_radix(fields[3], 16).ok()?;
+            let metric = fields[6].parse().ok()?;
+            usable_route(fields[0], flags, metric)
+        })
+        .collect()
+}
+
+/// Parse the default routes from the content of /proc/net/ipv6_route, where destination, prefix
+/// length, metric, flags and interface are the 1st, 2nd, 6th, 9th and 10th columns, in
+/// hexadecimal.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_ipv6_routes(content: &str) -> Vec<DefaultRoute> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[0].chars().any(|c| c != '0') || fields[1] != "00" {
+                return None;
+            }
+            let metric = u32::from_str_radix(fields[5], 16).ok()?;
+            let flags = u32::from_str_radix(fields[8], 16).ok()?;
+            usable_route(fields[9], flags, metric)
+        })
+        .collect()
+}
+
+/// Build a default route if its flags mark it as usable.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn usable_route(interface: &str, flags: u32, metric: u32) -> Option<DefaultRoute> {
+    if flags & RTF_UP == 0 || flags & RTF_REJECT != 0 {
+        return None;
+    }
+    Some(DefaultRoute {
+        interface: interface.to_string(),
+        metric,
+    })
+}
+
+/// Human readable flags of an interface.
+fn interface_flags(interface: &NetworkInterface) -> Vec<&'static str> {
+    let flags = [
+        (interface.is_up(), "UP"),
+        (interface.is_running(), "RUNNING"),
+        (interface.is_loopback(), "LOOPBACK"),
+        (interface.is_broadcast(), "BROADCAST"),
+        (interface.is_point_to_point(), "POINTOPOINT"),
+        (interface.is_multicast(), "MULTICAST"),
+    ];
+    flags.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const IFF_UP: u32 = 0x1;
+    const IFF_LOOPBACK: u32 = 0x8;
+
+    // The flags are a u32 on Linux and a u64 on the other platforms.
+    #[allow(clippy::useless_conversion)]
+    fn interface(name: &str, flags: u32, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index: 0,
+            mac: None,
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            flags: flags.into(),
+        }
+    }
+
+    #[test]
+    fn test_select_interface() {
+        let interfaces = vec![
+            interface("lo", IFF_UP | IFF_LOOPBACK, &["127.0.0.1/8", "::1/128"]),
+            interface("eth0", 0, &["10.0.0.2/24"]),
+            interface("wlan0", IFF_UP, &["fe80::1/64", "192.168.1.10/24"]),
+            interface("wlan1", IFF_UP, &["fe80::2/64", "2001:db8::2/64"]),
+        ];
+        let (selected, ip) = select_interface(&interfaces, &[], false).unwrap();
+        assert_eq!(selected.name, "wlan0");
+        assert_eq!(ip, "192.168.1.10".parse::<IpAddr>().unwrap());
+        let (selected, ip) = select_interface(&interfaces, &[], true).unwrap();
+        assert_eq!(selected.name, "wlan1");
+        assert_eq!(ip, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert!(select_interface(&interfaces[..2], &[], false).is_none());
+    }
+
+    #[test]
+    fn test_select_interface_ranking() {
+        let interfaces = vec![
+            interface("docker0", IFF_UP, &["172.17.0.1/16"]),
+            interface("wg0", IFF_UP, &["10.8.0.2/24"]),
+            interface("eth0", IFF_UP, &["203.0.113.5/24"]),
+            interface("wlan0", IFF_UP, &["192.168.1.10/24"]),
+            interface("eth1", IFF_UP, &["2001:db8::5/64", "fd00::5/64"]),
+        ];
+        let route = |name: &str, metric| DefaultRoute {
+            interface: name.to_string(),
+            metric,
+        };
+        let selected = |routes: &[DefaultRoute], ipv6| select_interface(&interfaces, routes, ipv6).unwrap();
+        assert_eq!(selected(&[], false).0.name, "wlan0");
+        assert_eq!(selected(&[route("eth0", 100)], false).0.name, "eth0");
+        assert_eq!(
+            selected(&[route("eth0", 600), route("wlan0", 100)], false).0.name,
+            "wlan0"
+        );
+        assert_eq!(selected(&[route("wg0", 0)], false).0.name, "wlan0");
+        assert_eq!(selected(&[], true).1, "fd00::5".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            select_interface(&interfaces[..2], &[], false).unwrap().0.name,
+            "docker0"
+        );
+    }
+
+    #[test]
+    fn test_rank_interfaces() {
+        let interfaces = vec![
+            interface("docker0", IFF_UP, &["172.17.0.1/16"]),
+            interface("eth0", IFF_UP, &["10.0.0.5/24"]),
+            interface("wlan0", IFF_UP, &["192.168.1.20/24"]),
+        ];
+        let routes = vec![DefaultRoute {
+            interface: "wlan0".to_string(),
+            metric: 600,
+        }];
+        let ranked: Vec<&str> = rank_interfaces(&interfaces, &routes, false)
+            .iter()
+            .map(|(i, _)| i.name.as_str())
+            .collect();
+        assert_eq!(ranked, vec!["wlan0", "eth0", "docker0"]);
+    }
+
+    #[test]
+    fn test_interface_addresses() {
+        let wlan0 = interface(
+            "wlan0",
+            IFF_UP,
+            &["2001:db8::20/64", "fe80::1/64", "fd00::20/64", "192.168.1.20/24"],
+        );
+        assert_eq!(
+            interface_addresses(&wlan0, true),
+            vec![
+                "fd00::20".parse::<IpAddr>().unwrap(),
+                "2001:db8::20".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert_eq!(
+            interface_addresses(&wlan0, false),
+            vec!["192.168.1.20".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_parse_ipv4_routes() {
+        let content = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+            wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0\n\
+            eth0\t00000000\t010200C0\t0003\t0\t0\t64\t00000000\t0\t0\t0\n\
+            tun0\t00000000\t00000000\t0000\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(
+            parse_ipv4_routes(content),
+            vec![
+                DefaultRoute {
+                    interface: "wlan0".to_string(),
+                    metric: 600
+                },
+                DefaultRoute {
+                    interface: "eth0".to_string(),
+                    metric: 64
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_ipv6_routes() {
+        let content = "fd000000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     eth0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n";
+        assert_eq!(
+            parse_ipv6_routes(content),
+            vec![DefaultRoute {
+                interface: "eth0".to_string(),
+                metric: 0x400
+            }]
+        );
+    }
+
+    #[test]
+    fn test_interface_flags() {
+        assert_eq!(
+            interface_flags(&interface("lo", IFF_UP | IFF_LOOPBACK, &[])),
+            vec!["UP", "LOOPBACK"]
+        );
+    }
+}
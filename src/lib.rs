@@ -10,21 +10,47 @@
 //!
 //! ### Acknowledgement
 //! * [qrcp](https://github.com/claudiodangelis/qrcp): I took many ideas from this amazing project
-//! and "stole" most of the HTML Bootstrap based UI.
+//!   and "stole" most of the HTML Bootstrap based UI.
 //! * [axum](https://github.com/tokio-rs/axum): A great HTTP framework for Rust, very expandable and simple to
-//! use.
+//!   use.
 //! * [qr2term](https://docs.rs/qr2term/): Terminal based QR rendering library.
 //! * [clap](https://clap.rs/): Oh man, where do I start telling how much do I love Clap?
 //!
 //! See Github project [README](https://github.com/crisidev/qrsync/blob/master/README.md) for more
 //! info.
 
+mod archive;
+mod auth;
+mod conflict;
 mod error;
 mod http;
+#[cfg(target_family = "unix")]
+mod interface;
+mod listener;
+mod partial;
+mod qr;
+mod render;
 mod routes;
+mod sanitize;
+mod session;
+mod shutdown;
+mod terminal;
+mod tls;
+mod transfer;
 
+pub use archive::ZipOptions;
+pub use conflict::OnConflict;
 pub use error::QrSyncError;
 pub use http::QrSyncHttp;
+#[cfg(target_family = "unix")]
+pub use interface::{interfaces, InterfaceInfo};
+pub use listener::{parse_bind_address, PortRange};
+pub use partial::cleanup_partial_files;
+pub use qr::QrImageOptions;
+pub use shutdown::ShutdownHandle;
+pub use terminal::QrRender;
+pub use tls::TlsCertificate;
+pub use transfer::Transfers;
 
 /// Handy type handling Result and Errors.
 pub type QrSyncResult<T> = Result<T, QrSyncError>;
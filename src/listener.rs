@@ -0,0 +1,305 @@
+//! TCP listeners and parsing of the addresses QrSync listens on and advertises.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener};
+use std::str::FromStr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{QrSyncError, QrSyncResult};
+
+/// Maximum number of pending connections on a listener.
+const BACKLOG: i32 = 1024;
+/// Attempts to find a port chosen by the system which is free on all the addresses.
+const SYSTEM_PORT_ATTEMPTS: usize = 10;
+
+/// Range of ports probed in order to find a free one. Port 0 lets the system choose a free port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    /// Create a new instance of PortRange, from the first to the last port included.
+    pub fn new(first: u16, last: u16) -> Self {
+        PortRange { first, last }
+    }
+
+    /// Ports to try, in order.
+    fn candidates(&self) -> Vec<u16> {
+        if self.first == 0 {
+            vec![0; SYSTEM_PORT_ATTEMPTS]
+        } else {
+            (self.first..=self.last).collect()
+        }
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        PortRange::new(port, port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range {s:?}, expected for example 5566-5600");
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+        let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(PortRange::new(first, last))
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// Whether an address is an IPv6 link-local address, only reachable through a zone identifier.
+pub(crate) fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
+
+/// Parse an address advertised in the QR code. IPv6 link-local addresses are refused: browsers
+/// do not accept zone identifiers in URLs, and the zone of the mobile device differs anyway.
+pub(crate) fn advertised_ip(ip_address: &str) -> QrSyncResult<IpAddr> {
+    let link_local = || {
+        QrSyncError::Error(format!(
+            "IPv6 link-local address {ip_address} cannot be advertised, use a global or unique local address"
+        ))
+    };
+    if ip_address.contains('%') {
+        return Err(link_local());
+    }
+    let ip = ip_address.parse()?;
+    if is_link_local(&ip) {
+        return Err(link_local());
+    }
+    Ok(ip)
+}
+
+/// Parse the address to listen on, like `0.0.0.0`, `[::]:5566` or `[fe80::1%eth0]:5566`. The
+/// port defaults to the given one. IPv6 link-local addresses require a zone identifier, either an
+/// interface name or index.
+pub fn parse_bind_address(value: &str, port: u16) -> QrSyncResult<SocketAddr> {
+    let invalid = || QrSyncError::Error(format!("Invalid bind address {value}"));
+    let value = value.trim();
+    let (host, port) = match value.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, port),
+                rest => (
+                    host,
+                    rest.strip_prefix(':')
+                        .and_then(|p| p.parse().ok())
+                        .ok_or_else(invalid)?,
+                ),
+            }
+        }
+        None => match value.rsplit_once(':') {
+            Some((host, p)) if !host.contains(':') => (host, p.parse().map_err(|_| invalid())?),
+            _ => (value, port),
+        },
+    };
+    let (host, zone) = match host.split_once('%') {
+        Some((host, zone)) => (host, Some(zone)),
+        None => (host, None),
+    };
+    let ip: IpAddr = host.parse().map_err(|_| invalid())?;
+    match (ip, zone) {
+        (IpAddr::V6(ip), Some(zone)) => {
+            let scope_id =
+                scope_id(zone).ok_or_else(|| QrSyncError::Error(format!("Unknown zone {zone} in bind address")))?;
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+        }
+        (IpAddr::V4(_), Some(_)) => Err(invalid()),
+        (ip, None) if is_link_local(&ip) => Err(QrSyncError::Error(format!(
+            "IPv6 link-local bind address {value} requires a zone, like [{ip}%eth0]"
+        ))),
+        (ip, None) => Ok(SocketAddr::new(ip, port)),
+    }
+}
+
+/// Scope identifier of a zone, given as an interface name or index.
+fn scope_id(zone: &str) -> Option<u32> {
+    if let Ok(index) = zone.parse() {
+        return Some(index);
+    }
+    #[cfg(target_family = "unix")]
+    return crate::interface::interface_index(zone);
+    #[cfg(not(target_family = "unix"))]
+    None
+}
+
+/// Bind a TCP listener on the given address. A listener on the IPv6 unspecified address `::` is
+/// dual-stack and accepts IPv4 connections too, whatever the system default is.
+pub(crate) fn bind_listener(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(not(target_family = "windows"))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Unspecified address QrSync listens on by default: `::`, dual-stack, or `0.0.0.0` when IPv6 is
+/// not available on the system.
+pub(crate) fn unspecified_address() -> IpAddr {
+    match bind_listener(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)) {
+        Ok(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        Err(e) => {
+            tracing::debug!("IPv6 is not available, listening on IPv4 only: {}", e);
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+    }
+}
+
+/// Bind a listener on every address, all on the same port: the first port of the range free on
+/// all of them, or a free port chosen by the system for port 0.
+pub(crate) fn bind_listeners(addresses: &[IpAddr], ports: PortRange) -> QrSyncResult<Vec<TcpListener>> {
+    for port in ports.candidates() {
+        match bind_same_port(addresses, port) {
+            Ok(listeners) => return Ok(listeners),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                tracing::debug!("Port {} is already in use, trying the next one", port)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if ports.first == ports.last {
+        return Err(QrSyncError::Error(format!(
+            "Port {} is already in use, choose another one with --port or --port-range",
+            ports
+        )));
+    }
+    Err(QrSyncError::Error(format!("No free port available in range {}", ports)))
+}
+
+/// Bind a listener on every address on the same port. The port chosen by the system for the first
+/// address is reused for the others.
+fn bind_same_port(addresses: &[IpAddr], mut port: u16) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(addresses.len());
+    for ip in addresses {
+        let listener = bind_listener(SocketAddr::new(*ip, port))?;
+        port = listener.local_addr()?.port();
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::{assert_eq, assert_ne};
+
+    #[test]
+    fn test_advertised_ip() {
+        assert_eq!(advertised_ip("fd00::2").unwrap(), "fd00::2".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            advertised_ip("10.0.0.1").unwrap(),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(advertised_ip("fe80::1").is_err());
+        assert!(advertised_ip("fe80::1%eth0").is_err());
+    }
+
+    #[test]
+    fn test_parse_bind_address() {
+        let parse = |value| parse_bind_address(value, 5566).unwrap().to_string();
+        assert_eq!(parse("0.0.0.0"), "0.0.0.0:5566");
+        assert_eq!(parse("0.0.0.0:8080"), "0.0.0.0:8080");
+        assert_eq!(parse("::"), "[::]:5566");
+        assert_eq!(parse("[::]"), "[::]:5566");
+        assert_eq!(parse("[fd00::2]:8080"), "[fd00::2]:8080");
+        assert_eq!(parse("[fe80::1%2]:8080"), "[fe80::1%2]:8080");
+        assert!(parse_bind_address("fe80::1", 5566).is_err());
+        assert!(parse_bind_address("10.0.0.1%2", 5566).is_err());
+        assert!(parse_bind_address("[::1]x", 5566).is_err());
+        assert!(parse_bind_address("myhost:8080", 5566).is_err());
+    }
+
+    #[test]
+    fn test_port_range() {
+        assert_eq!("5566-5600".parse(), Ok(PortRange::new(5566, 5600)));
+        assert_eq!("5566".parse(), Ok(PortRange::new(5566, 5566)));
+        assert!("5600-5566".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert_eq!(PortRange::new(5566, 5600).to_string(), "5566-5600");
+        assert_eq!(PortRange::new(5566, 5568).candidates(), vec![5566, 5567, 5568]);
+    }
+
+    /// Bind a busy port whose next port was free when checked.
+    fn busy_port(ip: IpAddr) -> (TcpListener, u16) {
+        for _ in 0..SYSTEM_PORT_ATTEMPTS {
+            let busy = bind_listener(SocketAddr::new(ip, 0)).unwrap();
+            let port = busy.local_addr().unwrap().port();
+            let next = match port.checked_add(1) {
+                Some(next) => next,
+                None => continue,
+            };
+            if bind_listener(SocketAddr::new(ip, next)).is_ok() {
+                return (busy, port);
+            }
+        }
+        panic!("Unable to find two consecutive free ports");
+    }
+
+    #[test]
+    fn test_bind_listeners_skips_busy_ports() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let (_busy, port) = busy_port(ip);
+        let listeners = bind_listeners(&[ip], PortRange::new(port, port + 1)).unwrap();
+        assert_ne!(listeners[0].local_addr().unwrap().port(), port);
+        assert!(bind_listeners(&[ip], PortRange::from(port)).is_err());
+    }
+
+    #[test]
+    fn test_bind_listeners_system_port() {
+        let ips: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        let listeners = bind_listeners(&ips, PortRange::from(0)).unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(listeners[1].local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_unspecified_address_dual_stack() {
+        let ip = unspecified_address();
+        assert!(ip.is_unspecified());
+        let listeners = bind_listeners(&[ip], PortRange::from(0)).unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_ok());
+        if ip.is_ipv6() {
+            assert!(std::net::TcpStream::connect(("::1", port)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_bind_listener_dual_stack() {
+        let listener = bind_listener("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_ok());
+        assert!(std::net::TcpStream::connect(("::1", port)).is_ok());
+    }
+}
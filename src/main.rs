@@ -1,40 +1,133 @@
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
 use argh::FromArgs;
-use qrsync::{QrSyncHttp, QrSyncResult};
+use axum::http::Uri;
+use qrsync::{
+    parse_bind_address, OnConflict, PortRange, QrImageOptions, QrRender, QrSyncError, QrSyncHttp, QrSyncResult,
+    ShutdownHandle, TlsCertificate, Transfers, ZipOptions,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 /// qrsync - copy files over WiFI using QR codes.
 #[derive(FromArgs, Debug)]
 struct Opts {
-    /// file to be send to the mobile device.
+    /// files or glob patterns to be sent to the mobile device.
     #[argh(positional)]
-    filename: Option<String>,
-    /// root directory to store files in receive mode.
+    filenames: Vec<String>,
+    /// root directory to store files in receive mode or to expose in browse mode.
     #[argh(option, short = 'r')]
     root_dir: Option<String>,
+    /// expose the root directory read-only as a browsable file tree.
+    #[argh(switch, short = 'b')]
+    browse: bool,
     /// enable QrSync debug.
     #[argh(switch, short = 'd')]
     debug: bool,
-    /// port to bind the HTTP server to.
+    /// port to bind the HTTP server to, 0 to let the system choose a free one.
     #[argh(option, short = 'p', default = "5566")]
     port: u16,
-    /// ip address to bind the HTTP server to. Default to primary interface.
+    /// range of ports to bind the HTTP server to, like 5566-5600, using the first free one.
+    #[argh(option)]
+    port_range: Option<PortRange>,
+    /// ip address advertised in the QR code. Default to primary interface.
     #[argh(option, short = 'i')]
     ip_address: Option<String>,
     /// draw QR in a terminal with light background.
     #[argh(switch, short = 'l')]
     light_term: bool,
+    /// how the QR code is drawn in the terminal: auto, blocks, compact, ascii, kitty, iterm or
+    /// sixel. auto uses the graphics protocol detected for the terminal, or blocks.
+    #[argh(option, default = "QrRender::Auto")]
+    qr_render: QrRender,
+    /// save the QR code as a PNG image to this path.
+    #[argh(option)]
+    qr_png: Option<PathBuf>,
+    /// save the QR code as an SVG image to this path.
+    #[argh(option)]
+    qr_svg: Option<PathBuf>,
+    /// size of a QR code module in the PNG and SVG images, in pixels.
+    #[argh(option, default = "8")]
+    qr_module_size: u32,
+    /// width of the quiet zone around the QR code in the PNG and SVG images, in modules.
+    #[argh(option, default = "4")]
+    qr_quiet_zone: u32,
+    /// do not print the QR code in the terminal, only save it with --qr-png or --qr-svg.
+    #[argh(switch)]
+    no_terminal_qr: bool,
     /// prefer IPv6 over IPv4.
     #[argh(switch, short = '6')]
     ipv6: bool,
+    /// policy for received files which already exist: rename, overwrite, skip or fail.
+    #[argh(option, default = "OnConflict::Rename")]
+    on_conflict: OnConflict,
+    /// compression level from 0 to 9 used to send directories as ZIP archives.
+    #[argh(option, default = "6")]
+    zip_level: u32,
+    /// include hidden files when sending directories as ZIP archives.
+    #[argh(switch)]
+    zip_hidden: bool,
+    /// require the mobile device to enter a PIN shown only in the terminal.
+    #[argh(switch)]
+    pin: bool,
+    /// serve HTTPS with an ephemeral self-signed certificate.
+    #[argh(switch)]
+    tls: bool,
+    /// PEM certificate to serve HTTPS with, requires --tls-key.
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key to serve HTTPS with, requires --tls-cert.
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+    /// exit after the first complete download in send and browse mode, or after the first
+    /// successful upload in receive mode.
+    #[argh(switch)]
+    once: bool,
+    /// exit after this number of complete downloads in send and browse mode.
+    #[argh(option)]
+    downloads: Option<usize>,
+    /// shut down after this time without requests, like 90s, 15m or 1h30m.
+    #[argh(option, from_str_fn(parse_duration))]
+    idle_timeout: Option<Duration>,
+    /// shut down after this time even if the session is in use, like 90s, 15m or 1h30m.
+    #[argh(option, from_str_fn(parse_duration))]
+    expires_in: Option<Duration>,
+    /// name of the network interface whose address is advertised. Default to primary interface.
+    #[argh(option)]
+    interface: Option<String>,
+    /// print a labeled QR code for each of the usable addresses.
+    #[argh(switch, short = 'a')]
+    all_addresses: bool,
+    /// address to listen on, like 0.0.0.0 or 192.168.1.20:5566. The port defaults to --port. By
+    /// default QrSync listens dual-stack on ::, or on 0.0.0.0 without IPv6.
+    #[argh(option)]
+    bind: Option<String>,
+    /// base URL advertised in the QR code, like http://myhost.lan:8080, when the mobile device
+    /// reaches QrSync through NAT, a container or port forwarding.
+    #[argh(option, from_str_fn(parse_public_url))]
+    public_url: Option<Uri>,
     /// show version info.
     #[argh(switch, short = 'v')]
     version: bool,
+    #[argh(subcommand)]
+    command: Option<Command>,
 }
 
+/// QrSync subcommands.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+enum Command {
+    Interfaces(InterfacesCommand),
+}
+
+/// list the network interfaces with their addresses and flags, marking the one selected
+/// automatically with a star.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "interfaces")]
+struct InterfacesCommand {}
+
 /// Setup `tracing::subscriber` to read the log level from RUST_LOG environment variable.
 fn setup_tracing(debug: bool) {
     let level = if debug { "debug" } else { "info" };
@@ -47,17 +140,113 @@ fn setup_tracing(debug: bool) {
         .init();
 }
 
-/// Register signal handlers for SIGTERM, SIGINT and SIGQUIT
-fn register_signal_handlers() -> QrSyncResult<()> {
-    ctrlc::set_handler(move || {
-        tracing::warn!("Shutting down QrSync server");
-        process::exit(0);
-    })?;
+/// Parse the base URL advertised in the QR code.
+fn parse_public_url(value: &str) -> Result<Uri, String> {
+    value.parse().map_err(|e| format!("Invalid public URL {value}: {e}"))
+}
+
+/// Parse a duration made of numbers followed by a unit among h, m and s, like 1h30m. Numbers
+/// without unit are seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration {value}, use for example 90s, 15m or 1h30m");
+    let mut secs = 0u64;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        secs = secs.saturating_add(n.saturating_mul(unit));
+        number.clear();
+    }
+    if !number.is_empty() {
+        secs = secs.saturating_add(number.parse::<u64>().map_err(|_| invalid())?);
+    }
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Print all the network interfaces, marking the one which would be selected automatically.
+#[cfg(target_family = "unix")]
+fn print_interfaces(ipv6: bool) -> QrSyncResult<()> {
+    let interfaces = qrsync::interfaces(ipv6);
+    let width = interfaces.iter().map(|i| i.name.len()).max().unwrap_or(0);
+    for interface in interfaces {
+        let line = format!(
+            "{} {:width$}  <{}>  {}",
+            if interface.selected { "*" } else { " " },
+            interface.name,
+            interface.flags.join(","),
+            interface.addresses.join(" "),
+            width = width
+        );
+        println!("{}", line.trim_end());
+    }
     Ok(())
 }
 
-/// Parse command line flags, configure logging, register signal handlers and run QrSync.
-async fn run() -> QrSyncResult<()> {
+/// Listing the network interfaces is not supported on windows, as the IP address is mandatory.
+#[cfg(target_family = "windows")]
+fn print_interfaces(_ipv6: bool) -> QrSyncResult<()> {
+    Err(QrSyncError::Error(
+        "Listing network interfaces is not supported on windows".into(),
+    ))
+}
+
+/// Exit status used when QrSync stops without completing any transfer.
+const EXIT_NO_TRANSFER: i32 = 2;
+
+/// Exit status telling whether any transfer has been completed.
+fn exit_status(transfers: &Transfers) -> i32 {
+    if transfers.completed() > 0 {
+        0
+    } else {
+        EXIT_NO_TRANSFER
+    }
+}
+
+/// Register signal handlers for SIGTERM, SIGINT and SIGQUIT. The first signal shuts the server
+/// down gracefully, letting the active transfers finish, while a second one interrupts them.
+fn register_signal_handlers(shutdown: ShutdownHandle) -> QrSyncResult<()> {
+    ctrlc::set_handler(move || shutdown.shutdown())?;
+    Ok(())
+}
+
+/// Expand the glob patterns passed on the command line, keeping the arguments which are not
+/// patterns or which match an existing file or directory literally.
+fn expand_filenames(patterns: Vec<String>) -> QrSyncResult<Vec<String>> {
+    let mut filenames = Vec::new();
+    for pattern in patterns {
+        if Path::new(&pattern).exists() || !pattern.contains(['*', '?', '[']) {
+            filenames.push(pattern);
+            continue;
+        }
+        let paths = glob::glob(&pattern).map_err(|e| QrSyncError::Error(format!("Invalid pattern {pattern}: {e}")))?;
+        let mut matched = false;
+        for path in paths {
+            let path = path.map_err(|e| QrSyncError::Error(format!("Unable to expand pattern {pattern}: {e}")))?;
+            filenames.push(path.display().to_string());
+            matched = true;
+        }
+        if !matched {
+            return Err(QrSyncError::Error(format!("Pattern {pattern} does not match any file")));
+        }
+    }
+    Ok(filenames)
+}
+
+/// Parse command line flags, configure logging, register signal handlers and run QrSync. Returns
+/// the exit status telling whether any transfer has been completed.
+async fn run() -> QrSyncResult<i32> {
     let opts: Opts = argh::from_env();
     if opts.version {
         println!("qrsync v{} - {}", env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_AUTHORS"));
@@ -65,30 +254,111 @@ async fn run() -> QrSyncResult<()> {
     }
     setup_tracing(opts.debug);
     tracing::debug!("Command line options are {:#?}", opts);
-    register_signal_handlers()?;
+    if let Some(Command::Interfaces(_)) = opts.command {
+        print_interfaces(opts.ipv6)?;
+        process::exit(0)
+    }
+    if opts.interface.is_some() && opts.ip_address.is_some() {
+        return Err(QrSyncError::Error(
+            "--interface and --ip-address cannot be used together".into(),
+        ));
+    }
+    if opts.all_addresses && opts.ip_address.is_some() {
+        return Err(QrSyncError::Error(
+            "--all-addresses and --ip-address cannot be used together".into(),
+        ));
+    }
+    if opts.all_addresses && (opts.bind.is_some() || opts.public_url.is_some()) {
+        return Err(QrSyncError::Error(
+            "--all-addresses cannot be used with --bind or --public-url".into(),
+        ));
+    }
+    if opts.port_range.is_some() && opts.bind.is_some() {
+        return Err(QrSyncError::Error(
+            "--port-range cannot be used with --bind, set the port in the bind address instead".into(),
+        ));
+    }
+    let bind = opts
+        .bind
+        .as_deref()
+        .map(|b| parse_bind_address(b, opts.port))
+        .transpose()?;
     let root_dir = match opts.root_dir {
         Some(r) => Path::new(&r).to_path_buf(),
         None => env::current_dir()?,
     };
-    let http = QrSyncHttp::new(
-        opts.ip_address,
-        opts.port,
-        opts.filename,
-        root_dir,
-        opts.light_term,
-        opts.ipv6,
-    );
+    let filenames = expand_filenames(opts.filenames)?;
+    let tls = match (opts.tls_cert, opts.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsCertificate::Files { cert, key }),
+        (None, None) if opts.tls => Some(TlsCertificate::SelfSigned),
+        (None, None) => None,
+        _ => {
+            return Err(QrSyncError::Error(
+                "--tls-cert and --tls-key must be used together".into(),
+            ))
+        }
+    };
+    if opts.browse && !filenames.is_empty() {
+        return Err(QrSyncError::Error(
+            "Browse mode cannot be used while sending files".into(),
+        ));
+    }
+    let transfer_limit = match (opts.once, opts.downloads) {
+        (true, Some(_)) => {
+            return Err(QrSyncError::Error(
+                "--once and --downloads cannot be used together".into(),
+            ))
+        }
+        (_, Some(0)) => return Err(QrSyncError::Error("--downloads must be greater than 0".into())),
+        (_, Some(_)) if !opts.browse && filenames.is_empty() => {
+            return Err(QrSyncError::Error("--downloads cannot be used in receive mode".into()))
+        }
+        (true, None) => Some(1),
+        (false, downloads) => downloads,
+    };
+    if opts.no_terminal_qr && opts.qr_png.is_none() && opts.qr_svg.is_none() {
+        return Err(QrSyncError::Error(
+            "--no-terminal-qr requires --qr-png or --qr-svg".into(),
+        ));
+    }
+    let qr_image_options = QrImageOptions::new(opts.qr_module_size, opts.qr_quiet_zone)?;
+    let http = QrSyncHttp::new(opts.ip_address, opts.port, None, root_dir, opts.light_term, opts.ipv6)
+        .with_filenames(filenames)
+        .with_terminal_qr(!opts.no_terminal_qr)
+        .with_qr_render(opts.qr_render)
+        .with_qr_png(opts.qr_png)
+        .with_qr_svg(opts.qr_svg)
+        .with_qr_image_options(qr_image_options)
+        .with_interface(opts.interface)
+        .with_all_addresses(opts.all_addresses)
+        .with_port_range(opts.port_range)
+        .with_bind(bind)
+        .with_public_url(opts.public_url)
+        .with_on_conflict(opts.on_conflict)
+        .with_zip_options(ZipOptions::new(opts.zip_level, opts.zip_hidden))
+        .with_browse(opts.browse)
+        .with_pin(opts.pin)
+        .with_tls(tls)
+        .with_transfer_limit(transfer_limit)
+        .with_idle_timeout(opts.idle_timeout)
+        .with_expires_in(opts.expires_in);
+    let transfers = http.transfers();
+    register_signal_handlers(http.shutdown_handle())?;
     http.run().await?;
-    Ok(())
+    Ok(exit_status(&transfers))
 }
 
 /// The main!
 #[tokio::main]
 async fn main() -> ! {
     match run().await {
-        Ok(_) => {
-            tracing::info!("QrSync run successfully");
-            process::exit(0);
+        Ok(status) => {
+            if status == EXIT_NO_TRANSFER {
+                tracing::warn!("QrSync stopped without completing any transfer");
+            } else {
+                tracing::info!("QrSync run successfully");
+            }
+            process::exit(status);
         }
         Err(e) => {
             tracing::error!("Error running QrSync: {}", e);
@@ -0,0 +1,125 @@
+//! Temporary files used to receive data before moving it in place atomically.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::QrSyncResult;
+
+/// Prefix of the partial files, unique for this process.
+const PARTIAL_PREFIX: &str = ".qrsync-";
+/// Suffix of the partial files.
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// Counter used to generate unique partial file names inside this process.
+static PARTIAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Hidden temporary file inside the root directory receiving the content of an upload. The file
+/// is removed when dropped, so a failed or interrupted transfer never leaves partial data behind.
+#[derive(Debug)]
+pub(crate) struct PartialFile {
+    file: Option<File>,
+    path: PathBuf,
+}
+
+impl PartialFile {
+    /// Create a new hidden partial file inside `root_dir`.
+    pub(crate) async fn create(root_dir: &Path) -> QrSyncResult<Self> {
+        let id = PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = root_dir.join(format!("{}{}-{}{}", PARTIAL_PREFIX, process::id(), id, PARTIAL_SUFFIX));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path).await?;
+        Ok(PartialFile { file: Some(file), path })
+    }
+
+    /// Path of the partial file.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a chunk of data to the partial file.
+    pub(crate) async fn write_all(&mut self, data: &[u8]) -> QrSyncResult<()> {
+        if let Some(f) = self.file.as_mut() {
+            f.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush and fsync the content of the partial file and close it, so it can be moved in place.
+    pub(crate) async fn finish(&mut self) -> QrSyncResult<()> {
+        if let Some(mut f) = self.file.take() {
+            f.flush().await?;
+            f.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        self.file.take();
+        match std::fs::remove_file(&self.path) {
+            Ok(_) => tracing::debug!("Removed partial file {}", self.path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => tracing::warn!("Unable to remove partial file {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Whether a file name follows the pattern of the partial files of any QrSync process.
+pub(crate) fn is_partial_file_name(name: &str) -> bool {
+    name.starts_with(PARTIAL_PREFIX) && name.ends_with(PARTIAL_SUFFIX)
+}
+
+/// Remove the partial files left by this process inside `root_dir`. This is meant to be called on
+/// shutdown, when in-flight transfers are interrupted.
+pub fn cleanup_partial_files(root_dir: &Path) {
+    let prefix = format!("{}{}-", PARTIAL_PREFIX, process::id());
+    let entries = match std::fs::read_dir(root_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::warn!("Unable to list partial files in {}: {}", root_dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&prefix) && name.ends_with(PARTIAL_SUFFIX) {
+            match std::fs::remove_file(entry.path()) {
+                Ok(_) => tracing::info!("Removed partial file {}", entry.path().display()),
+                Err(e) => tracing::warn!("Unable to remove partial file {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_partial_file_removed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut partial = PartialFile::create(dir.path()).await.unwrap();
+        partial.write_all(b"data").await.unwrap();
+        let path = partial.path().to_path_buf();
+        assert!(path.exists());
+        drop(partial);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_partial_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial = PartialFile::create(dir.path()).await.unwrap();
+        std::fs::write(dir.path().join("keep.txt"), "").unwrap();
+        cleanup_partial_files(dir.path());
+        assert!(!partial.path().exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
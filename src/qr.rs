@@ -0,0 +1,212 @@
+//! QR code images saved to PNG and SVG files, rendered from the same matrix printed in the
+//! terminal.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use qr2term::matrix::Matrix;
+use qr2term::render::{Color, QrDark};
+
+use crate::{QrSyncError, QrSyncResult};
+
+/// PNG file signature.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Largest module size, keeping images of the biggest QR codes below a few megabytes.
+const MAX_MODULE_SIZE: u32 = 64;
+
+/// Size of the QR code images saved to files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QrImageOptions {
+    module_size: u32,
+    quiet_zone: u32,
+}
+
+impl Default for QrImageOptions {
+    fn default() -> Self {
+        QrImageOptions {
+            module_size: 8,
+            quiet_zone: 4,
+        }
+    }
+}
+
+impl QrImageOptions {
+    /// Create a new instance of QrImageOptions, with the size of a module in pixels and the width
+    /// of the quiet zone around the QR code in modules.
+    pub fn new(module_size: u32, quiet_zone: u32) -> QrSyncResult<Self> {
+        if module_size == 0 || module_size > MAX_MODULE_SIZE {
+            return Err(QrSyncError::Error(format!(
+                "QR code module size must be between 1 and {MAX_MODULE_SIZE} pixels"
+            )));
+        }
+        Ok(QrImageOptions {
+            module_size,
+            quiet_zone,
+        })
+    }
+
+    /// Width of the image in modules, quiet zone included.
+    fn modules(&self, matrix: &Matrix<Color>) -> usize {
+        matrix.size() + 2 * self.quiet_zone as usize
+    }
+
+    /// Whether the module at the given position of the image is dark, the quiet zone being light.
+    fn is_dark(&self, matrix: &Matrix<Color>, x: usize, y: usize) -> bool {
+        let quiet_zone = self.quiet_zone as usize;
+        let size = matrix.size();
+        if x < quiet_zone || y < quiet_zone || x >= size + quiet_zone || y >= size + quiet_zone {
+            return false;
+        }
+        matrix.pixels()[(y - quiet_zone) * size + x - quiet_zone] == QrDark
+    }
+}
+
+/// Render the QR code as a grayscale PNG image, dark modules on a light background.
+pub(crate) fn render_png(matrix: &Matrix<Color>, options: &QrImageOptions) -> QrSyncResult<Vec<u8>> {
+    let modules = options.modules(matrix);
+    let module_size = options.module_size as usize;
+    let width = modules * module_size;
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for y in 0..modules {
+        let mut row = Vec::with_capacity(width + 1);
+        // Filter type None.
+        row.push(0);
+        for x in 0..modules {
+            let value = if options.is_dark(matrix, x, y) { 0x00 } else { 0xff };
+            row.extend(std::iter::repeat_n(value, module_size));
+        }
+        for _ in 0..module_size {
+            encoder.write_all(&row)?;
+        }
+    }
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    // Bit depth 8, grayscale, deflate compression, adaptive filtering, no interlace.
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &encoder.finish()?);
+    write_png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+/// Append a PNG chunk with its length and checksum.
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Render the QR code as an SVG image, dark modules on a light background. Every dark module is
+/// a unit square of a single path, scaled to the module size by the view box.
+pub(crate) fn render_svg(matrix: &Matrix<Color>, options: &QrImageOptions) -> String {
+    let modules = options.modules(matrix);
+    let width = modules * options.module_size as usize;
+    let mut path = String::new();
+    for y in 0..modules {
+        for x in 0..modules {
+            if options.is_dark(matrix, x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x, y));
+            }
+        }
+    }
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{width}\" ",
+            "viewBox=\"0 0 {modules} {modules}\" shape-rendering=\"crispEdges\">\n",
+            "<rect width=\"{modules}\" height=\"{modules}\" fill=\"#ffffff\"/>\n",
+            "<path fill=\"#000000\" d=\"{path}\"/>\n",
+            "</svg>\n"
+        ),
+        width = width,
+        modules = modules,
+        path = path
+    )
+}
+
+/// Path of the image of one of several QR codes, numbered from 1 before the extension.
+pub(crate) fn numbered_path(path: &Path, index: Option<usize>) -> PathBuf {
+    let index = match index {
+        Some(index) => index,
+        None => return path.to_path_buf(),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
+/// Save the QR code as a PNG image.
+pub(crate) fn save_png(matrix: &Matrix<Color>, options: &QrImageOptions, path: &Path) -> QrSyncResult<()> {
+    fs::write(path, render_png(matrix, options)?)?;
+    tracing::info!("QR code saved to {}", path.display());
+    Ok(())
+}
+
+/// Save the QR code as an SVG image.
+pub(crate) fn save_svg(matrix: &Matrix<Color>, options: &QrImageOptions, path: &Path) -> QrSyncResult<()> {
+    fs::write(path, render_svg(matrix, options))?;
+    tracing::info!("QR code saved to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use qr2term::render::QrLight;
+
+    fn matrix() -> Matrix<Color> {
+        Matrix::new(vec![QrDark, QrLight, QrLight, QrDark])
+    }
+
+    #[test]
+    fn test_render_png() {
+        let options = QrImageOptions::new(2, 1).unwrap();
+        let png = render_png(&matrix(), &options).unwrap();
+        assert_eq!(&png[..8], PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        // 2 modules plus 1 module of quiet zone on each side, 2 pixels each.
+        assert_eq!(&png[16..24], &[0, 0, 0, 8, 0, 0, 0, 8]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let options = QrImageOptions::new(10, 1).unwrap();
+        let svg = render_svg(&matrix(), &options);
+        assert!(svg.contains("width=\"40\" height=\"40\" viewBox=\"0 0 4 4\""));
+        assert!(svg.contains("d=\"M1,1h1v1h-1zM2,2h1v1h-1z\""));
+    }
+
+    #[test]
+    fn test_numbered_path() {
+        assert_eq!(
+            numbered_path(Path::new("/tmp/qr.png"), None),
+            PathBuf::from("/tmp/qr.png")
+        );
+        assert_eq!(
+            numbered_path(Path::new("/tmp/qr.png"), Some(2)),
+            PathBuf::from("/tmp/qr-2.png")
+        );
+        assert_eq!(numbered_path(Path::new("qr"), Some(1)), PathBuf::from("qr-1"));
+    }
+
+    #[test]
+    fn test_image_options() {
+        assert!(QrImageOptions::new(0, 4).is_err());
+        assert!(QrImageOptions::new(MAX_MODULE_SIZE + 1, 4).is_err());
+        assert_eq!(QrImageOptions::new(8, 4).unwrap(), QrImageOptions::default());
+    }
+}
@@ -1,160 +1,520 @@
 //! Axum routes definitions.
 
+use std::any::Any;
+use std::fs::Metadata;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Arc;
 
-use axum::body::{Bytes, Full};
+use axum::body::StreamBody;
+use axum::extract::multipart::Field;
 use axum::extract::{Multipart, Path as AxumPath, State};
 use axum::http::{header, StatusCode};
 use axum::response::{Html, IntoResponse, Redirect, Response};
 use base64::{engine::general_purpose, Engine as _};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 
+use crate::archive::{zip_stream, ZipOptions};
+use crate::conflict::OnConflict;
 use crate::error::QrSyncError;
+use crate::partial::PartialFile;
+use crate::render::{encode_path_segment, escape_html, format_size, format_time};
+use crate::sanitize::sanitize_file_name;
+use crate::transfer::Transfers;
 use crate::QrSyncResult;
 
+const SEND_HTML: &str = include_str!("templates/send.html");
+const BROWSE_HTML: &str = include_str!("templates/browse.html");
 const POST_HTML: &str = include_str!("templates/post.html");
 const DONE_HTML: &str = include_str!("templates/done.html");
 const ERROR_HTML: &str = include_str!("templates/error.html");
 const BOOTSTRAP_CSS: &str = include_str!("templates/bootstrap.min.css");
 const BOOTSTRAP_CSS_MAP: &str = include_str!("templates/bootstrap.min.css.map");
+const SESSION_JS: &str = include_str!("templates/session.js");
 
 /// Request context structure, passed between Axum handlers to share state.
 pub(crate) struct QrSyncState {
-    file_name: Option<String>,
+    file_names: Vec<String>,
     root_dir: PathBuf,
+    on_conflict: OnConflict,
+    zip_options: ZipOptions,
+    browse: bool,
+    transfers: Arc<Transfers>,
+}
+
+/// Content served for a file requested in send mode.
+enum Download {
+    /// Regular file, streamed with its size.
+    File(File, u64),
+    /// Directory, streamed as a ZIP archive built on the fly.
+    Directory(PathBuf),
 }
 
 impl QrSyncState {
-    pub(crate) fn new(file_name: Option<String>, root_dir: &Path) -> Self {
+    pub(crate) fn new(
+        file_names: Vec<String>,
+        root_dir: &Path,
+        on_conflict: OnConflict,
+        zip_options: ZipOptions,
+        browse: bool,
+        transfers: Arc<Transfers>,
+    ) -> Self {
         QrSyncState {
-            file_name,
+            file_names,
             root_dir: root_dir.to_path_buf(),
+            on_conflict,
+            zip_options,
+            browse,
+            transfers,
+        }
+    }
+
+    /// Ensure QrSync is running in send mode, returning the served files.
+    fn ensure_send_mode(&self) -> QrSyncResult<&[String]> {
+        if self.file_names.is_empty() {
+            tracing::error!("QrSync is not running in send mode");
+            return Err(QrSyncError::WrongMode("QrSync is not running in send mode".into()));
+        }
+        Ok(&self.file_names)
+    }
+
+    /// Ensure QrSync is running in receive mode, as uploads are not accepted in send mode.
+    fn ensure_receive_mode(&self) -> QrSyncResult<()> {
+        if !self.file_names.is_empty() || self.browse {
+            tracing::error!("QrSync is not running in receive mode");
+            return Err(QrSyncError::WrongMode("QrSync is not running in receive mode".into()));
+        }
+        Ok(())
+    }
+
+    /// Ensure QrSync is running in browse mode, as the root directory is not exposed otherwise.
+    fn ensure_browse_mode(&self) -> QrSyncResult<()> {
+        if !self.browse {
+            tracing::error!("QrSync is not running in browse mode");
+            return Err(QrSyncError::WrongMode("QrSync is not running in browse mode".into()));
         }
+        Ok(())
     }
 
-    async fn download_file(&self, file_name: &str) -> QrSyncResult<Vec<u8>> {
-        match self.file_name.as_ref() {
+    /// Resolve a path relative to the root directory in browse mode. Paths with parent directory
+    /// components and symlinks pointing outside of the root directory are refused.
+    async fn resolve_browse_path(&self, rel_path: &str) -> QrSyncResult<PathBuf> {
+        self.ensure_browse_mode()?;
+        if rel_path.split(['/', '\\']).any(|c| c == "..") {
+            return Err(QrSyncError::Forbidden(format!(
+                "Path {} escapes the root directory",
+                rel_path
+            )));
+        }
+        let root = tokio::fs::canonicalize(&self.root_dir).await?;
+        let path = tokio::fs::canonicalize(root.join(rel_path.trim_start_matches('/'))).await?;
+        if !path.starts_with(&root) {
+            tracing::error!("Refusing to serve {} outside of the root directory", path.display());
+            return Err(QrSyncError::Forbidden(format!(
+                "Path {} escapes the root directory",
+                rel_path
+            )));
+        }
+        Ok(path)
+    }
+
+    /// List the entries of a directory in browse mode, sorted with directories first. Symlinks
+    /// pointing outside of the root directory are not listed.
+    async fn list_directory(&self, dir: &Path) -> QrSyncResult<Vec<(String, Metadata)>> {
+        let root = tokio::fs::canonicalize(&self.root_dir).await?;
+        let mut entries = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            match tokio::fs::canonicalize(&path).await {
+                Ok(target) if target.starts_with(&root) => (),
+                _ => continue,
+            }
+            let metadata = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            entries.push((entry.file_name().to_string_lossy().to_string(), metadata));
+        }
+        entries.sort_by(|(a, am), (b, bm)| bm.is_dir().cmp(&am.is_dir()).then_with(|| a.cmp(b)));
+        Ok(entries)
+    }
+
+    /// Open the file requested in send mode, returning its name and its content, so that the
+    /// content can be streamed in chunks instead of being buffered in memory. Directories are
+    /// served as ZIP archives.
+    async fn download_file(&self, file_name: &str) -> QrSyncResult<(String, Download)> {
+        let served = self.ensure_send_mode()?;
+        let encoded_file_name = general_purpose::URL_SAFE_NO_PAD.decode(file_name)?;
+        let decoded_file_name = str::from_utf8(&encoded_file_name)?;
+        match served.iter().find(|f| *f == decoded_file_name) {
             Some(stored_filename) => {
-                let encoded_file_name = general_purpose::URL_SAFE_NO_PAD.decode(file_name)?;
-                let decoded_file_name = str::from_utf8(&encoded_file_name)?;
-                if stored_filename == decoded_file_name {
-                    let file_path = self.root_dir.join(stored_filename);
-                    let mut buffer = Vec::new();
-                    let mut f = File::open(file_path).await?;
-                    f.read_to_end(&mut buffer).await?;
-                    Ok(buffer)
-                } else {
-                    tracing::error!(
-                        "Requested file {} differs from served one {}",
-                        decoded_file_name,
-                        stored_filename
-                    );
-                    Err(QrSyncError::Error("Requested file differs from served one".into()))
+                let file_path = self.root_dir.join(stored_filename);
+                if tokio::fs::metadata(&file_path).await?.is_dir() {
+                    return Ok((
+                        format!("{}.zip", display_name(&file_path)),
+                        Download::Directory(file_path),
+                    ));
                 }
+                let f = File::open(&file_path).await?;
+                let size = f.metadata().await?.len();
+                Ok((display_name(&file_path), Download::File(f, size)))
             }
             None => {
-                tracing::error!("QrSync is not running in send mode");
-                Err(QrSyncError::Error("QrSync is not running in send mode".into()))
+                tracing::error!("Requested file {} is not among the served ones", decoded_file_name);
+                Err(QrSyncError::NotFound(
+                    "Requested file is not among the served ones".into(),
+                ))
+            }
+        }
+    }
+
+    /// List the files served in send mode with their name, size and download link. Directories
+    /// have no size, as they are archived on the fly.
+    async fn list_files(&self) -> QrSyncResult<Vec<(String, Option<u64>, String)>> {
+        let mut files = Vec::new();
+        for stored_filename in self.ensure_send_mode()? {
+            let file_path = self.root_dir.join(stored_filename);
+            let metadata = tokio::fs::metadata(&file_path).await?;
+            let link = format!("/{}", general_purpose::URL_SAFE_NO_PAD.encode(stored_filename));
+            if metadata.is_dir() {
+                files.push((format!("{}.zip", display_name(&file_path)), None, link));
+            } else {
+                files.push((display_name(&file_path), Some(metadata.len()), link));
             }
         }
+        Ok(files)
     }
 
-    /// Copy a file from a source to a destination. The file_name and content_type are used to produce
-    /// nice errors.
-    async fn copy_file(&self, content_type: &str, src: Bytes, dst: &Path) {
-        match File::create(dst).await {
-            Ok(mut f) => match f.write_all(&src).await {
-                Ok(_) => tracing::info!(
-                    "Received file with content-type {} stored in {}",
+    /// Stream a multipart field to a file named file_name inside the root directory, writing each
+    /// chunk as soon as it arrives so that memory usage stays bounded regardless of the uploaded
+    /// file size. Name collisions are resolved according to the configured conflict policy. The
+    /// content_type is used to produce nice logs.
+    async fn copy_file(&self, content_type: &str, field: Field<'_>, file_name: &str) -> FileResult {
+        let mut size = 0;
+        let outcome = match self.write_field(field, file_name, &mut size).await {
+            Ok(Some(dst)) => {
+                tracing::info!(
+                    "Received file with content-type {} stored in {} ({} bytes)",
+                    content_type,
+                    dst.display(),
+                    size
+                );
+                FileOutcome::Stored(dst)
+            }
+            Ok(None) => FileOutcome::Skipped,
+            Err(e) => {
+                tracing::error!(
+                    "Unable to store file {} with content-type {}: {}",
+                    file_name,
                     content_type,
-                    dst.display()
-                ),
-                Err(e) => tracing::error!("Unable to store file {:?} to {}: {}", self.file_name, dst.display(), e),
-            },
-            Err(e) => tracing::error!("Unable to store file {:?} to {}: {}", self.file_name, dst.display(), e),
+                    e
+                );
+                FileOutcome::Failed(e)
+            }
+        };
+        FileResult {
+            name: file_name.to_string(),
+            size,
+            outcome,
         }
     }
+
+    /// Write all the chunks of a multipart field into a hidden partial file, which is fsynced and
+    /// moved in place only once the field is complete. Returns the final path, or `None` if the
+    /// file has been skipped, while size tracks the number of bytes written.
+    async fn write_field(
+        &self,
+        mut field: Field<'_>,
+        file_name: &str,
+        size: &mut u64,
+    ) -> QrSyncResult<Option<PathBuf>> {
+        if !self.on_conflict.precheck(&self.root_dir, file_name).await? {
+            return Ok(None);
+        }
+        let mut partial = PartialFile::create(&self.root_dir).await?;
+        while let Some(chunk) = field.chunk().await? {
+            partial.write_all(&chunk).await?;
+            *size += chunk.len() as u64;
+        }
+        partial.finish().await?;
+        self.on_conflict
+            .persist(partial.path(), &self.root_dir, file_name)
+            .await
+    }
+}
+
+/// Outcome of the transfer of a single file received in receive mode.
+#[derive(Debug)]
+pub(crate) enum FileOutcome {
+    /// The file has been stored at the given path.
+    Stored(PathBuf),
+    /// The file already existed and has been skipped.
+    Skipped,
+    /// The file could not be stored.
+    Failed(QrSyncError),
+}
+
+/// Result of the transfer of a single file, shown to the user on the done page.
+#[derive(Debug)]
+pub(crate) struct FileResult {
+    name: String,
+    size: u64,
+    outcome: FileOutcome,
+}
+
+/// Render the done page with the results of every file received in a single upload. The status
+/// code is the one of the first failure if any of the files failed to be stored.
+fn render_done(results: &[FileResult]) -> (StatusCode, Html<String>) {
+    let failed: Vec<&QrSyncError> = results
+        .iter()
+        .filter_map(|r| match &r.outcome {
+            FileOutcome::Failed(e) => Some(e),
+            _ => None,
+        })
+        .collect();
+    let (status, alert_class, title, message) = if results.is_empty() {
+        (
+            StatusCode::OK,
+            "alert-warning",
+            "Nothing to do mate!",
+            "No file was received.".to_string(),
+        )
+    } else if failed.is_empty() {
+        (
+            StatusCode::OK,
+            "alert-success",
+            "All done mate!",
+            "Files successfully transferred. You can close this page now.".to_string(),
+        )
+    } else {
+        (
+            failed[0].status_code(),
+            "alert-danger",
+            "Something went wrong!",
+            format!("{} of {} files could not be transferred.", failed.len(), results.len()),
+        )
+    };
+    let mut rows = String::new();
+    for result in results {
+        let (class, outcome) = match &result.outcome {
+            FileOutcome::Stored(dst) => (
+                "table-success",
+                format!("Stored as {}", dst.file_name().unwrap_or_default().to_string_lossy()),
+            ),
+            FileOutcome::Skipped => ("table-warning", "Skipped, file already exists".to_string()),
+            FileOutcome::Failed(e) => ("table-danger", e.to_string()),
+        };
+        rows.push_str(&format!(
+            "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td></tr>",
+            class,
+            escape_html(&result.name),
+            format_size(result.size),
+            escape_html(&outcome)
+        ));
+    }
+    let table = if rows.is_empty() {
+        rows
+    } else {
+        format!(
+            "<table class=\"table\"><thead><tr><th>File</th><th>Size</th><th>Result</th></tr></thead><tbody>{}</tbody></table>",
+            rows
+        )
+    };
+    let body = DONE_HTML
+        .replace("###ALERTCLASS###", alert_class)
+        .replace("###TITLE###", title)
+        .replace("###MESSAGE###", &message)
+        .replace("###RESULTS###", &table);
+    (status, Html(body))
 }
 
+/// Serve GET /:file_name URL in send mode, streaming the requested file to the client.
 pub(crate) async fn get_send(
     AxumPath(file_name): AxumPath<String>,
     State(state): State<Arc<QrSyncState>>,
-) -> impl IntoResponse {
-    match state.download_file(&file_name).await {
-        Ok(data) => {
-            let decoded_file_name = general_purpose::URL_SAFE_NO_PAD
-                .decode(&file_name)
-                .map_err(|e| {
-                    let e: QrSyncError = e.into();
-                    e.into_response()
-                })
-                .unwrap();
-            let decoded_file_name = str::from_utf8(&decoded_file_name)
-                .map_err(|e| {
-                    let e: QrSyncError = e.into();
-                    e.into_response()
-                })
-                .unwrap();
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header(
-                    header::CONTENT_DISPOSITION,
-                    format!("attachment; filename=\"{}\"", decoded_file_name),
-                )
-                .body(Full::from(data))
-                .unwrap())
+) -> QrSyncResult<Response> {
+    let (name, download) = state.download_file(&file_name).await?;
+    let response = match download {
+        Download::File(file, size) => file_response(&state.transfers, &name, file, size),
+        Download::Directory(dir) => (
+            [
+                (header::CONTENT_DISPOSITION, content_disposition(&name)),
+                (header::CONTENT_TYPE, "application/zip".to_string()),
+            ],
+            StreamBody::new(state.transfers.track(zip_stream(dir, state.zip_options), None)),
+        )
+            .into_response(),
+    };
+    Ok(response)
+}
+
+/// Build the response streaming a file as an attachment, recording a transfer once the whole file
+/// has been sent.
+fn file_response(transfers: &Arc<Transfers>, name: &str, file: File, size: u64) -> Response {
+    (
+        [
+            (header::CONTENT_DISPOSITION, content_disposition(name)),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        StreamBody::new(transfers.track(ReaderStream::new(file), Some(size))),
+    )
+        .into_response()
+}
+
+/// Serve GET /browse URL in browse mode, listing the root directory.
+pub(crate) async fn get_browse_root(State(state): State<Arc<QrSyncState>>) -> QrSyncResult<Response> {
+    browse(&state, "").await
+}
+
+/// Serve GET /browse/*path URL in browse mode, listing directories and downloading files.
+pub(crate) async fn get_browse(
+    AxumPath(rel_path): AxumPath<String>,
+    State(state): State<Arc<QrSyncState>>,
+) -> QrSyncResult<Response> {
+    browse(&state, &rel_path).await
+}
+
+/// List a directory or download a file below the root directory in browse mode.
+async fn browse(state: &QrSyncState, rel_path: &str) -> QrSyncResult<Response> {
+    let path = state.resolve_browse_path(rel_path).await?;
+    if !tokio::fs::metadata(&path).await?.is_dir() {
+        let f = File::open(&path).await?;
+        let size = f.metadata().await?.len();
+        return Ok(file_response(&state.transfers, &display_name(&path), f, size));
+    }
+    let segments: Vec<&str> = rel_path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut link = "/browse/".to_string();
+    let mut breadcrumbs = String::from("<li class=\"breadcrumb-item\"><a href=\"/browse/\">Home</a></li>");
+    for segment in segments.iter() {
+        link.push_str(&encode_path_segment(segment));
+        link.push('/');
+        breadcrumbs.push_str(&format!(
+            "<li class=\"breadcrumb-item\"><a href=\"{}\">{}</a></li>",
+            escape_html(&link),
+            escape_html(segment)
+        ));
+    }
+    let mut rows = String::new();
+    for (name, metadata) in state.list_directory(&path).await? {
+        let modified = metadata.modified().map(format_time).unwrap_or_default();
+        let (href, name, size) = if metadata.is_dir() {
+            (
+                format!("{}{}/", link, encode_path_segment(&name)),
+                format!("{}/", name),
+                "-".to_string(),
+            )
+        } else {
+            (
+                format!("{}{}", link, encode_path_segment(&name)),
+                name,
+                format_size(metadata.len()),
+            )
+        };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            escape_html(&href),
+            escape_html(&name),
+            size,
+            modified
+        ));
+    }
+    let body = BROWSE_HTML
+        .replace("###BREADCRUMBS###", &breadcrumbs)
+        .replace("###ENTRIES###", &rows);
+    Ok(Html(body).into_response())
+}
+
+/// Name of a served file shown to the user, without the leading directories.
+fn display_name(file_path: &Path) -> String {
+    file_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| file_path.display().to_string())
+}
+
+/// Serve GET /send URL in send mode, listing all the served files with their size and a link to
+/// download them.
+pub(crate) async fn get_send_index(State(state): State<Arc<QrSyncState>>) -> QrSyncResult<impl IntoResponse> {
+    let mut rows = String::new();
+    for (name, size, link) in state.list_files().await? {
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td></tr>",
+            escape_html(&link),
+            escape_html(&name),
+            size.map(format_size).unwrap_or_else(|| "ZIP archive".to_string())
+        ));
+    }
+    Ok(Html(SEND_HTML.replace("###FILES###", &rows)))
+}
+
+/// Build the Content-Disposition header for an attachment, with an ASCII fallback name for old
+/// clients and the UTF-8 encoded name as described in RFC 6266.
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut encoded = String::new();
+    for b in file_name.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
         }
-        Err(_) => Err(Redirect::to("/error")),
     }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
 }
 
 /// Serve POST /receive URL parsing the multipart form. This way multiple files with different
-/// names can be received in a single session.
-pub(crate) async fn post_receive(State(state): State<Arc<QrSyncState>>, mut multipart: Multipart) -> impl IntoResponse {
-    while let Some(field) = multipart
-        .next_field()
-        .await
-        .map_err(|e| {
-            let e: QrSyncError = e.into();
-            e.into_response()
-        })
-        .unwrap()
-    {
+/// names can be received in a single session. The done page reports the result of every file.
+pub(crate) async fn post_receive(
+    State(state): State<Arc<QrSyncState>>,
+    mut multipart: Multipart,
+) -> QrSyncResult<impl IntoResponse> {
+    state.ensure_receive_mode()?;
+    let mut results = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
         let content_type = field.content_type().unwrap_or("text/plain").to_string();
         if let Some(file_name) = field.file_name() {
             if !file_name.is_empty() {
-                let file_path = state.root_dir.join(file_name);
-                state
-                    .copy_file(
-                        &content_type,
-                        field
-                            .bytes()
-                            .await
-                            .map_err(|e| {
-                                let e: QrSyncError = e.into();
-                                e.into_response()
-                            })
-                            .unwrap(),
-                        &file_path,
-                    )
-                    .await;
+                match sanitize_file_name(file_name) {
+                    Ok(file_name) => results.push(state.copy_file(&content_type, field, &file_name).await),
+                    Err(e) => {
+                        tracing::error!("Refusing to store uploaded file: {}", e);
+                        results.push(FileResult {
+                            name: file_name.to_string(),
+                            size: 0,
+                            outcome: FileOutcome::Failed(e),
+                        });
+                    }
+                }
             }
         }
     }
-    Redirect::to("/receive_done")
+    if is_successful_batch(&results) {
+        state.transfers.complete();
+    }
+    Ok(render_done(&results))
 }
 
-/// Serve GET /receive URL where the user can input files and text to receive.
-pub(crate) async fn get_receive() -> impl IntoResponse {
-    Html(POST_HTML.to_string())
+/// An upload batch is successful when at least one file has been stored and none has failed.
+fn is_successful_batch(results: &[FileResult]) -> bool {
+    results.iter().any(|r| matches!(r.outcome, FileOutcome::Stored(_)))
+        && !results.iter().any(|r| matches!(r.outcome, FileOutcome::Failed(_)))
 }
 
-/// Serve GET /done URL where we redirect upon success.
-pub(crate) async fn get_receive_done() -> impl IntoResponse {
-    Html(DONE_HTML.to_string())
+/// Serve GET /receive URL where the user can input files and text to receive.
+pub(crate) async fn get_receive(State(state): State<Arc<QrSyncState>>) -> QrSyncResult<impl IntoResponse> {
+    state.ensure_receive_mode()?;
+    Ok(Html(POST_HTML.to_string()))
 }
 
 /// Serve GET /error URL where we redirect upon errors,
@@ -164,11 +524,7 @@ pub(crate) async fn get_error() -> impl IntoResponse {
 
 /// Serve Bootstrap minimized CSS as static file.
 pub(crate) async fn static_bootstrap_css() -> impl IntoResponse {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "text/css")
-        .body(Full::from(BOOTSTRAP_CSS.to_string()))
-        .unwrap()
+    ([(header::CONTENT_TYPE, "text/css")], BOOTSTRAP_CSS)
 }
 
 /// Serve Bootstrap minimized CSS map as static file.
@@ -176,13 +532,14 @@ pub(crate) async fn static_bootstrap_css_map() -> impl IntoResponse {
     BOOTSTRAP_CSS_MAP.to_string()
 }
 
+/// Serve the script showing the session expired message as static file.
+pub(crate) async fn static_session_js() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/javascript")], SESSION_JS)
+}
+
 /// Serve a fake favicon to avoid getting errors if the favicon is requested.
 pub(crate) async fn static_favicon() -> impl IntoResponse {
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "image/webp")
-        .body(Full::from("hi".to_string()))
-        .unwrap()
+    ([(header::CONTENT_TYPE, "image/webp")], "hi")
 }
 
 /// Rickroll curious cats :)
@@ -194,3 +551,131 @@ pub(crate) async fn slash() -> impl IntoResponse {
 pub(crate) async fn bad_request() -> impl IntoResponse {
     (StatusCode::IM_A_TEAPOT, Html(ERROR_HTML.to_string()))
 }
+
+/// Turn a panic inside a handler into an error page, so a client can never crash the server.
+pub(crate) fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let details = if let Some(s) = err.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = err.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        "unknown panic".to_string()
+    };
+    tracing::error!("Handler panicked: {}", details);
+    QrSyncError::Error("Internal server error".into()).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Root directory with a file and a directory, next to an outside directory holding a secret
+    /// file, and symlinks from the root to both outside entries.
+    fn browse_tree() -> (tempfile::TempDir, QrSyncState) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(root.join("docs/notes.txt"), "notes").unwrap();
+        std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+        #[cfg(target_family = "unix")]
+        {
+            std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret-link")).unwrap();
+            std::os::unix::fs::symlink(&outside, root.join("outside-link")).unwrap();
+            std::os::unix::fs::symlink(root.join("docs"), root.join("docs-link")).unwrap();
+        }
+        let state = QrSyncState::new(
+            vec![],
+            &root,
+            OnConflict::Rename,
+            ZipOptions::default(),
+            true,
+            Arc::new(Transfers::new(None)),
+        );
+        (dir, state)
+    }
+
+    fn is_forbidden<T>(result: QrSyncResult<T>) -> bool {
+        matches!(result, Err(QrSyncError::Forbidden(_)))
+    }
+
+    #[tokio::test]
+    async fn test_resolve_browse_path() {
+        let (dir, state) = browse_tree();
+        let root = std::fs::canonicalize(dir.path().join("root")).unwrap();
+        assert_eq!(state.resolve_browse_path("").await.unwrap(), root);
+        assert_eq!(
+            state.resolve_browse_path("docs/notes.txt").await.unwrap(),
+            root.join("docs/notes.txt")
+        );
+        // A leading slash stays relative to the root directory.
+        assert_eq!(state.resolve_browse_path("/docs").await.unwrap(), root.join("docs"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_browse_path_parent_components() {
+        let (_dir, state) = browse_tree();
+        assert!(is_forbidden(state.resolve_browse_path("../outside/secret.txt").await));
+        assert!(is_forbidden(state.resolve_browse_path("docs/../../outside").await));
+        assert!(is_forbidden(state.resolve_browse_path("..\\outside\\secret.txt").await));
+        assert!(is_forbidden(state.resolve_browse_path("..").await));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_browse_path_absolute() {
+        let (dir, state) = browse_tree();
+        let secret = dir.path().join("outside/secret.txt");
+        let resolved = state.resolve_browse_path(&secret.to_string_lossy()).await;
+        assert!(resolved.is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_resolve_browse_path_escaping_symlinks() {
+        let (dir, state) = browse_tree();
+        let root = std::fs::canonicalize(dir.path().join("root")).unwrap();
+        assert!(is_forbidden(state.resolve_browse_path("secret-link").await));
+        assert!(is_forbidden(state.resolve_browse_path("outside-link").await));
+        assert!(is_forbidden(state.resolve_browse_path("outside-link/secret.txt").await));
+        assert_eq!(
+            state.resolve_browse_path("docs-link/notes.txt").await.unwrap(),
+            root.join("docs/notes.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_browse_path_wrong_mode() {
+        let (dir, _) = browse_tree();
+        let state = QrSyncState::new(
+            vec![],
+            &dir.path().join("root"),
+            OnConflict::Rename,
+            ZipOptions::default(),
+            false,
+            Arc::new(Transfers::new(None)),
+        );
+        assert!(matches!(
+            state.resolve_browse_path("docs").await,
+            Err(QrSyncError::WrongMode(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_directory_hides_escaping_symlinks() {
+        let (dir, state) = browse_tree();
+        let root = std::fs::canonicalize(dir.path().join("root")).unwrap();
+        let names: Vec<String> = state
+            .list_directory(&root)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        #[cfg(target_family = "unix")]
+        assert_eq!(names, vec!["docs", "docs-link"]);
+        #[cfg(not(target_family = "unix"))]
+        assert_eq!(names, vec!["docs"]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Sanitization of file names received from mobile devices.
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::partial::is_partial_file_name;
+use crate::{QrSyncError, QrSyncResult};
+
+/// Maximum length in bytes of a file name on most filesystems.
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Characters that are not allowed in file names on Windows.
+#[cfg(target_family = "windows")]
+const WINDOWS_INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names that are reserved on Windows, regardless of the extension.
+#[cfg(target_family = "windows")]
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turn a file name sent by the client into a name which is safe to join with the root directory.
+///
+/// The name is normalized to Unicode NFC and any directory component is stripped, so only the
+/// final file name is kept. Names containing `..` components, NUL or control characters are
+/// rejected, as well as names which would be invalid on the host filesystem and names of partial
+/// files, which are removed when QrSync is interrupted.
+pub(crate) fn sanitize_file_name(file_name: &str) -> QrSyncResult<String> {
+    let normalized: String = file_name.nfc().collect();
+    if normalized.chars().any(|c| c.is_control()) {
+        return Err(QrSyncError::InvalidFileName(format!(
+            "{:?} contains NUL or control characters",
+            file_name
+        )));
+    }
+    let mut components = normalized.split(['/', '\\']);
+    if components.any(|c| c == "..") {
+        return Err(QrSyncError::InvalidFileName(format!(
+            "{:?} contains a parent directory component",
+            file_name
+        )));
+    }
+    let name = normalized.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = sanitize_for_host(name);
+    if name.is_empty() || name == "." {
+        return Err(QrSyncError::InvalidFileName(format!("{:?} is empty", file_name)));
+    }
+    if name.len() > MAX_FILE_NAME_LEN {
+        return Err(QrSyncError::InvalidFileName(format!(
+            "{:?} is longer than {} bytes",
+            file_name, MAX_FILE_NAME_LEN
+        )));
+    }
+    check_reserved_name(file_name, &name)?;
+    if is_partial_file_name(&name) {
+        return Err(QrSyncError::InvalidFileName(format!(
+            "{:?} is reserved for the partial files of QrSync",
+            file_name
+        )));
+    }
+    Ok(name)
+}
+
+/// Replace characters which are not allowed by the host filesystem.
+#[cfg(target_family = "windows")]
+fn sanitize_for_host(name: &str) -> String {
+    name.chars()
+        .map(|c| if WINDOWS_INVALID_CHARS.contains(&c) { '_' } else { c })
+        .collect::<String>()
+        .trim_end_matches(['.', ' '])
+        .to_string()
+}
+
+/// Replace characters which are not allowed by the host filesystem.
+#[cfg(target_family = "unix")]
+fn sanitize_for_host(name: &str) -> String {
+    name.to_string()
+}
+
+/// Reject names which are reserved by the host filesystem.
+#[cfg(target_family = "windows")]
+fn check_reserved_name(file_name: &str, name: &str) -> QrSyncResult<()> {
+    let stem = name.split('.').next().unwrap_or_default();
+    if WINDOWS_RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return Err(QrSyncError::InvalidFileName(format!(
+            "{:?} is a reserved device name",
+            file_name
+        )));
+    }
+    Ok(())
+}
+
+/// Reject names which are reserved by the host filesystem.
+#[cfg(target_family = "unix")]
+fn check_reserved_name(_file_name: &str, _name: &str) -> QrSyncResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_sanitize_file_name_plain() {
+        assert_eq!(sanitize_file_name("IMG_0001.jpg").unwrap(), "IMG_0001.jpg");
+    }
+
+    #[test]
+    fn test_sanitize_file_name_strips_directories() {
+        assert_eq!(sanitize_file_name("/etc/passwd").unwrap(), "passwd");
+        assert_eq!(sanitize_file_name("dir/sub/file.txt").unwrap(), "file.txt");
+        assert_eq!(sanitize_file_name("C:\\Users\\me\\file.txt").unwrap(), "file.txt");
+    }
+
+    #[test]
+    fn test_sanitize_file_name_rejects_traversal() {
+        assert!(sanitize_file_name("../../.ssh/authorized_keys").is_err());
+        assert!(sanitize_file_name("..").is_err());
+        assert!(sanitize_file_name("a\\..\\b").is_err());
+    }
+
+    #[test]
+    fn test_sanitize_file_name_rejects_invalid() {
+        assert!(sanitize_file_name("").is_err());
+        assert!(sanitize_file_name("dir/").is_err());
+        assert!(sanitize_file_name("file\0.txt").is_err());
+        assert!(sanitize_file_name("file\n.txt").is_err());
+        assert!(sanitize_file_name(&"a".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn test_sanitize_file_name_rejects_partial_files() {
+        assert!(sanitize_file_name(".qrsync-1234-0.part").is_err());
+        assert!(sanitize_file_name("dir/.qrsync-1-2.part").is_err());
+        assert_eq!(sanitize_file_name(".qrsync-notes.txt").unwrap(), ".qrsync-notes.txt");
+        assert_eq!(sanitize_file_name("video.part").unwrap(), "video.part");
+    }
+
+    #[test]
+    fn test_sanitize_file_name_normalizes_unicode() {
+        assert_eq!(sanitize_file_name("cafe\u{301}.txt").unwrap(), "caf\u{e9}.txt");
+    }
+}
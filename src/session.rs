@@ -0,0 +1,314 @@
+//! Session lifetime, ending after a period without requests or at an absolute deadline.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::body::{boxed, BoxBody, Bytes, HttpBody};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Json, Response};
+use hyper::body::SizeHint;
+use serde::Serialize;
+
+use crate::render::format_duration;
+use crate::QrSyncError;
+
+/// Path polled by the pages to know the remaining session time, without counting as activity.
+const SESSION_PATH: &str = "/session";
+/// Interval between two remaining-time reports in the terminal.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// Interval between two remaining-time reports in the terminal during the last minute.
+const LAST_MINUTE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lifetime of a QrSync session. The session expires once no request has been served for the
+/// idle timeout, or once the hard expiry deadline has been reached, whichever comes first.
+#[derive(Debug)]
+pub(crate) struct Session {
+    idle_timeout: Option<Duration>,
+    expires_at: Option<Instant>,
+    activity: Mutex<Activity>,
+}
+
+/// Requests being served and time of the last one.
+#[derive(Debug)]
+struct Activity {
+    last: Instant,
+    in_flight: usize,
+}
+
+/// Remaining session time returned to the pages, in seconds.
+#[derive(Debug, Serialize)]
+struct SessionStatus {
+    remaining: Option<u64>,
+}
+
+impl Session {
+    /// Create a new instance of Session starting now.
+    pub(crate) fn new(idle_timeout: Option<Duration>, expires_in: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Session {
+            idle_timeout,
+            expires_at: expires_in.map(|d| now + d),
+            activity: Mutex::new(Activity {
+                last: now,
+                in_flight: 0,
+            }),
+        }
+    }
+
+    /// Whether the session can expire at all.
+    pub(crate) fn is_limited(&self) -> bool {
+        self.idle_timeout.is_some() || self.expires_at.is_some()
+    }
+
+    /// Instant when the session expires if no other request arrives. The idle timeout does not
+    /// run while requests are being served, until their response bodies have been fully sent, so
+    /// long transfers are never cut short by it.
+    fn deadline(&self) -> Option<Instant> {
+        let idle_deadline = match self.activity.lock() {
+            Ok(activity) if activity.in_flight == 0 => self.idle_timeout.map(|t| activity.last + t),
+            _ => None,
+        };
+        match (idle_deadline, self.expires_at) {
+            (Some(idle), Some(expiry)) => Some(idle.min(expiry)),
+            (idle, expiry) => idle.or(expiry),
+        }
+    }
+
+    /// Time left before the session expires, if it can expire.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.deadline().map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether the session has expired.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Number of requests being served, response bodies included.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.activity.lock().map(|a| a.in_flight).unwrap_or_default()
+    }
+
+    /// Record the start or the end of a request.
+    fn update(&self, started: bool) {
+        if let Ok(mut activity) = self.activity.lock() {
+            if started {
+                activity.in_flight += 1;
+            } else {
+                activity.in_flight = activity.in_flight.saturating_sub(1);
+            }
+            activity.last = Instant::now();
+        }
+    }
+
+    /// Wait until the session expires. Never resolves if the session cannot expire.
+    pub(crate) async fn expired(&self) {
+        if !self.is_limited() {
+            return std::future::pending().await;
+        }
+        loop {
+            match self.remaining() {
+                Some(Duration::ZERO) => break,
+                Some(remaining) => tokio::time::sleep(remaining).await,
+                // Requests are in flight, check again once they are likely over.
+                None => tokio::time::sleep(LAST_MINUTE_REPORT_INTERVAL).await,
+            }
+        }
+        tracing::warn!("Session expired, shutting down QrSync server");
+    }
+
+    /// Report the remaining session time in the terminal, every minute and more often during the
+    /// last minute. Never resolves if the session cannot expire.
+    pub(crate) async fn report(&self) {
+        if !self.is_limited() {
+            return std::future::pending().await;
+        }
+        loop {
+            let interval = match self.remaining() {
+                Some(remaining) if remaining > Duration::ZERO => {
+                    tracing::info!("Session expires in {}", format_duration(remaining));
+                    if remaining > REPORT_INTERVAL {
+                        REPORT_INTERVAL
+                    } else {
+                        LAST_MINUTE_REPORT_INTERVAL
+                    }
+                }
+                Some(_) => return,
+                None => REPORT_INTERVAL,
+            };
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// Request being served, from the time it arrives until its response body has been sent or
+/// dropped.
+struct InFlight(Arc<Session>);
+
+impl InFlight {
+    fn new(session: Arc<Session>) -> Self {
+        session.update(true);
+        InFlight(session)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.update(false);
+    }
+}
+
+/// Response body keeping its request in flight while it is streamed.
+struct TrackedBody {
+    inner: BoxBody,
+    _in_flight: InFlight,
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware refusing requests once the session has expired and keeping the session alive
+/// while requests are served, streamed downloads included. Polling the remaining time does not
+/// count as activity.
+pub(crate) async fn track_session<B>(State(session): State<Arc<Session>>, req: Request<B>, next: Next<B>) -> Response {
+    if session.is_expired() {
+        tracing::warn!("Refusing request to {} after the session expired", req.uri().path());
+        return QrSyncError::Expired.into_response();
+    }
+    if req.uri().path() == SESSION_PATH {
+        return next.run(req).await;
+    }
+    let in_flight = InFlight::new(session);
+    next.run(req).await.map(|inner| {
+        boxed(TrackedBody {
+            inner,
+            _in_flight: in_flight,
+        })
+    })
+}
+
+/// Serve GET /session URL with the remaining session time, used by the pages to show the session
+/// expired message.
+pub(crate) async fn get_session(State(session): State<Arc<Session>>) -> impl IntoResponse {
+    let status = SessionStatus {
+        remaining: session
+            .remaining()
+            .map(|r| r.as_secs() + u64::from(r.subsec_nanos() > 0)),
+    };
+    ([(header::CACHE_CONTROL, "no-store")], Json(status))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_unlimited_session() {
+        let session = Session::new(None, None);
+        assert!(!session.is_limited());
+        assert_eq!(session.remaining(), None);
+        assert!(!session.is_expired());
+    }
+
+    #[test]
+    fn test_hard_expiry() {
+        let session = Session::new(None, Some(Duration::ZERO));
+        assert!(session.is_expired());
+        let session = Session::new(Some(Duration::from_secs(60)), Some(Duration::from_secs(3600)));
+        assert!(session.remaining().unwrap() <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_idle_timeout_paused_while_in_flight() {
+        let session = Session::new(Some(Duration::ZERO), None);
+        assert!(session.is_expired());
+        session.update(true);
+        assert!(!session.is_expired());
+        assert_eq!(session.remaining(), None);
+        session.update(false);
+        assert!(session.is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_paused_while_streaming() {
+        use axum::body::{Body, StreamBody};
+        use axum::routing::get;
+        use axum::{middleware, Router};
+        use tower::ServiceExt;
+
+        let idle_timeout = Duration::from_millis(50);
+        let session = Arc::new(Session::new(Some(idle_timeout), None));
+        let app = Router::new()
+            .route(
+                "/download",
+                get(|| async {
+                    let chunks = futures_util::stream::unfold(0, |i| async move {
+                        if i == 3 {
+                            return None;
+                        }
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Some((Ok::<_, std::io::Error>(Bytes::from("chunk")), i + 1))
+                    });
+                    StreamBody::new(chunks)
+                }),
+            )
+            .layer(middleware::from_fn_with_state(session.clone(), track_session));
+        let request = Request::builder().uri("/download").body(Body::empty()).unwrap();
+        let mut body = app.oneshot(request).await.unwrap().into_body();
+        let mut received = 0;
+        while let Some(chunk) = body.data().await {
+            received += chunk.unwrap().len();
+            assert!(!session.is_expired());
+            assert_eq!(session.remaining(), None);
+        }
+        assert_eq!(received, 15);
+        // The transfer took three times the idle timeout, which only starts once the body is gone.
+        assert!(!session.is_expired());
+        drop(body);
+        assert!(session.remaining().is_some());
+        tokio::time::sleep(idle_timeout).await;
+        assert!(session.is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_dropped_body() {
+        let session = Arc::new(Session::new(Some(Duration::ZERO), None));
+        let body = TrackedBody {
+            inner: boxed(axum::body::Empty::new()),
+            _in_flight: InFlight::new(session.clone()),
+        };
+        assert!(!session.is_expired());
+        drop(body);
+        assert!(session.is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_expired() {
+        let session = Session::new(Some(Duration::from_millis(10)), None);
+        session.expired().await;
+        assert!(session.is_expired());
+    }
+}
@@ -0,0 +1,100 @@
+//! Graceful shutdown requested by signal handlers or library users.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// Handle used to stop a running QrSync server. The first request stops accepting connections
+/// and lets the active transfers finish, the second one interrupts them. Cloned handles control
+/// the same server, and requests made before the server starts are honored once it runs.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+/// Number of shutdown requests and notifications of the graceful and forced shutdown.
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requests: AtomicUsize,
+    graceful: Notify,
+    forced: Notify,
+}
+
+impl ShutdownHandle {
+    /// Create a new instance of ShutdownHandle.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the server to shut down, gracefully the first time and forcibly afterwards.
+    pub fn shutdown(&self) {
+        let requests = self.inner.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        if requests == 1 {
+            tracing::warn!("Shutting down QrSync server, waiting for active transfers to finish");
+            self.inner.graceful.notify_one();
+        } else {
+            tracing::warn!("Forcing QrSync server shut down, interrupting active transfers");
+            self.inner.forced.notify_one();
+        }
+    }
+
+    /// Record that a graceful shutdown started without a request, once the transfers are done or
+    /// the session expired, so that the next request forces it.
+    pub(crate) fn begin_graceful(&self) {
+        self.inner.requests.fetch_max(1, Ordering::SeqCst);
+    }
+
+    /// Wait for the first shutdown request.
+    pub(crate) async fn graceful(&self) {
+        self.inner.graceful.notified().await
+    }
+
+    /// Wait for a second shutdown request.
+    pub(crate) async fn forced(&self) {
+        self.inner.forced.notified().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shutdown_before_waiting() {
+        let handle = ShutdownHandle::new();
+        handle.clone().shutdown();
+        handle.graceful().await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), handle.forced())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_second_shutdown_forces() {
+        let handle = ShutdownHandle::new();
+        let forced = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.forced().await }
+        });
+        handle.shutdown();
+        handle.shutdown();
+        forced.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_after_automatic_graceful_forces() {
+        let handle = ShutdownHandle::new();
+        handle.begin_graceful();
+        let forced = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.forced().await }
+        });
+        handle.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), forced)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
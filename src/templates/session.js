@@ -0,0 +1,47 @@
+// Show the session expired message once the QrSync session is over. The remaining time is
+// refreshed periodically, since other requests keep an idle session alive.
+(function () {
+  var deadline = null;
+  var timer = null;
+
+  function expire() {
+    clearInterval(timer);
+    document.body.innerHTML =
+      '<div class="container"><div class="alert alert-warning" role="alert">' +
+      '<h4 class="alert-heading">Session expired</h4>' +
+      "<p>This QrSync session has expired and no more files can be transferred. Run QrSync " +
+      "again on the computer and scan the new QR code to start another session.</p>" +
+      "</div></div>";
+  }
+
+  function check() {
+    if (deadline !== null && Date.now() >= deadline) {
+      expire();
+    }
+  }
+
+  function refresh() {
+    fetch("/session", { credentials: "same-origin" })
+      .then(function (response) {
+        if (response.status === 410) {
+          return { remaining: 0 };
+        }
+        return response.json();
+      })
+      .then(function (session) {
+        if (session.remaining === null) {
+          clearInterval(timer);
+          return;
+        }
+        deadline = Date.now() + session.remaining * 1000;
+        check();
+      })
+      .catch(check);
+  }
+
+  timer = setInterval(function () {
+    check();
+    refresh();
+  }, 5000);
+  refresh();
+})();
@@ -0,0 +1,491 @@
+//! QR code renderers for the terminal, with characters or with the bitmap graphics protocols,
+//! falling back to the bare URL when the QR code does not fit.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use base64::{engine::general_purpose, Engine as _};
+use crossterm::tty::IsTty;
+use qr2term::matrix::Matrix;
+use qr2term::render::{Color, QrDark, QrLight, Renderer};
+
+use crate::qr::{render_png, QrImageOptions};
+use crate::QrSyncResult;
+
+/// Quiet zone of the colored renderer, in modules.
+const BLOCKS_QUIET_ZONE: usize = 2;
+/// Quiet zone of the monochrome renderers, in modules. Scanners fail often with less than two,
+/// even when the terminal background around the QR code extends it.
+const MONOCHROME_QUIET_ZONE: usize = 2;
+/// Quadrant blocks indexed by their filled quadrants: upper left, upper right, lower left and lower
+/// right from the lowest bit.
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+/// Size of a module of the bitmaps, in pixels. Assuming cells twice as high as wide, the bitmaps
+/// take as many cells as the colored half blocks, plus the frame on light terminals.
+const BITMAP_MODULE_SIZE: usize = 8;
+/// Dark frame drawn around the light quiet zone of the bitmaps on light terminals, in modules.
+const BITMAP_FRAME: usize = 1;
+/// Size of the base64 chunks sent with the kitty graphics protocol.
+const KITTY_CHUNK_SIZE: usize = 4096;
+/// Terminal names supporting sixel, as reported by the TERM environment variable.
+const SIXEL_TERMS: &[&str] = &["foot", "mlterm", "yaft", "contour"];
+
+/// How the QR code is drawn in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QrRender {
+    /// Colored half blocks, two modules per character cell, drawn with explicit colors.
+    Blocks,
+    /// Monochrome quadrant blocks, four modules per character cell drawn with the terminal
+    /// colors, half as wide as the colored half blocks to fit split panes. The modules are twice
+    /// as high as wide, which most scanners accept.
+    Compact,
+    /// Pure ASCII, two characters per module, for terminals without Unicode.
+    Ascii,
+    /// Bitmap drawn with the kitty graphics protocol.
+    Kitty,
+    /// Bitmap drawn with the iTerm2 inline images protocol.
+    Iterm,
+    /// Bitmap drawn with sixel graphics.
+    Sixel,
+    /// Bitmap drawn with the graphics protocol supported by the terminal, or colored half blocks
+    /// when none is detected.
+    #[default]
+    Auto,
+}
+
+impl QrRender {
+    /// Resolve the automatic renderer from the environment of the terminal.
+    fn resolve(self) -> Self {
+        match self {
+            QrRender::Auto if io::stdout().is_tty() => detect(|name| std::env::var(name).ok()),
+            QrRender::Auto => QrRender::Blocks,
+            render => render,
+        }
+    }
+}
+
+impl FromStr for QrRender {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(QrRender::Blocks),
+            "compact" => Ok(QrRender::Compact),
+            "ascii" => Ok(QrRender::Ascii),
+            "kitty" => Ok(QrRender::Kitty),
+            "iterm" => Ok(QrRender::Iterm),
+            "sixel" => Ok(QrRender::Sixel),
+            "auto" => Ok(QrRender::Auto),
+            _ => Err(format!(
+                "invalid QR code renderer {s:?}, expected one of auto, blocks, compact, ascii, kitty, iterm, sixel"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for QrRender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QrRender::Blocks => "blocks",
+            QrRender::Compact => "compact",
+            QrRender::Ascii => "ascii",
+            QrRender::Kitty => "kitty",
+            QrRender::Iterm => "iterm",
+            QrRender::Sixel => "sixel",
+            QrRender::Auto => "auto",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Detect the graphics protocol supported by the terminal from its environment variables. Inside
+/// tmux and screen, which do not forward graphics reliably, colored half blocks are used.
+fn detect<F: Fn(&str) -> Option<String>>(var: F) -> QrRender {
+    let term = var("TERM").unwrap_or_default();
+    let term_program = var("TERM_PROGRAM").unwrap_or_default();
+    if var("TMUX").is_some() || term.starts_with("screen") || term.starts_with("tmux") {
+        QrRender::Blocks
+    } else if var("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" || term == "xterm-ghostty" {
+        QrRender::Kitty
+    } else if term_program == "iTerm.app" || term_program == "WezTerm" {
+        QrRender::Iterm
+    } else if SIXEL_TERMS.iter().any(|t| term.starts_with(t)) {
+        QrRender::Sixel
+    } else {
+        QrRender::Blocks
+    }
+}
+
+/// Surround the QR code matrix with the quiet zone of the colored renderer, generating white
+/// based QRs on dark terminals and black based QRs on light terminals.
+pub(crate) fn blocks_matrix(mut matrix: Matrix<Color>, light_term: bool) -> Matrix<Color> {
+    if light_term {
+        matrix.surround(BLOCKS_QUIET_ZONE, QrDark);
+    } else {
+        matrix.surround(BLOCKS_QUIET_ZONE, QrLight);
+    }
+    matrix
+}
+
+/// Terminal QR code, drawn with its size in character cells.
+#[derive(Debug)]
+struct TerminalQr {
+    text: String,
+    columns: usize,
+    rows: usize,
+}
+
+/// Draw the QR code with the requested renderer. On terminals with a light background the dark
+/// modules are drawn with the foreground color, on dark terminals the light ones are. The bitmaps
+/// keep a light quiet zone, framed in dark on light terminals like the colored half blocks.
+fn draw(matrix: Matrix<Color>, render: QrRender, light_term: bool) -> QrSyncResult<TerminalQr> {
+    let ink = if light_term { QrDark } else { QrLight };
+    match render {
+        QrRender::Kitty | QrRender::Iterm | QrRender::Sixel => {
+            let matrix = bitmap_matrix(matrix, light_term);
+            let columns = matrix.size();
+            let rows = columns.div_ceil(2);
+            let text = match render {
+                QrRender::Sixel => sixel(&matrix),
+                QrRender::Iterm => {
+                    let png = render_png(&matrix, &bitmap_options())?;
+                    format!(
+                        "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=1:{}\x07\n",
+                        png.len(),
+                        columns,
+                        rows,
+                        general_purpose::STANDARD.encode(&png)
+                    )
+                }
+                _ => kitty(&render_png(&matrix, &bitmap_options())?, columns, rows),
+            };
+            Ok(TerminalQr { text, columns, rows })
+        }
+        QrRender::Blocks | QrRender::Auto => {
+            let matrix = blocks_matrix(matrix, light_term);
+            let renderer = Renderer::default();
+            let mut text = Vec::new();
+            renderer.render(&matrix, &mut text)?;
+            Ok(TerminalQr {
+                text: String::from_utf8_lossy(&text).into_owned(),
+                columns: renderer.width(&matrix),
+                rows: renderer.height(&matrix),
+            })
+        }
+        QrRender::Compact => {
+            let mut matrix = matrix;
+            matrix.surround(MONOCHROME_QUIET_ZONE, QrLight);
+            let size = matrix.size();
+            let pixels = matrix.pixels();
+            let is_ink = |row: usize, col: usize| row < size && col < size && pixels[row * size + col] == ink;
+            let mut text = String::new();
+            for row in (0..size).step_by(2) {
+                for col in (0..size).step_by(2) {
+                    let quadrants = usize::from(is_ink(row, col))
+                        | usize::from(is_ink(row, col + 1)) << 1
+                        | usize::from(is_ink(row + 1, col)) << 2
+                        | usize::from(is_ink(row + 1, col + 1)) << 3;
+                    text.push(QUADRANTS[quadrants]);
+                }
+                text.push('\n');
+            }
+            Ok(TerminalQr {
+                text,
+                columns: size.div_ceil(2),
+                rows: size.div_ceil(2),
+            })
+        }
+        QrRender::Ascii => {
+            let mut matrix = matrix;
+            matrix.surround(MONOCHROME_QUIET_ZONE, QrLight);
+            let size = matrix.size();
+            let mut text = String::new();
+            for row in matrix.pixels().chunks(size.max(1)) {
+                for pixel in row {
+                    text.push_str(if *pixel == ink { "##" } else { "  " });
+                }
+                text.push('\n');
+            }
+            Ok(TerminalQr {
+                text,
+                columns: 2 * size,
+                rows: size,
+            })
+        }
+    }
+}
+
+/// Surround the QR code matrix with the light quiet zone of the bitmaps, as large as the one of the
+/// colored half blocks. On light terminals, where the colored half blocks draw a dark quiet zone,
+/// the light one is framed in dark instead, so the bitmaps still stand out and scan.
+fn bitmap_matrix(mut matrix: Matrix<Color>, light_term: bool) -> Matrix<Color> {
+    matrix.surround(BLOCKS_QUIET_ZONE, QrLight);
+    if light_term {
+        matrix.surround(BITMAP_FRAME, QrDark);
+    }
+    matrix
+}
+
+/// Size of the bitmaps, the quiet zone being already part of the matrix.
+fn bitmap_options() -> QrImageOptions {
+    QrImageOptions::new(BITMAP_MODULE_SIZE as u32, 0).unwrap_or_default()
+}
+
+/// Transmit and display a PNG image with the kitty graphics protocol, split in chunks, scaled to
+/// the given number of cells.
+fn kitty(png: &[u8], columns: usize, rows: usize) -> String {
+    let data = general_purpose::STANDARD.encode(png);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    let mut text = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let chunk = String::from_utf8_lossy(chunk);
+        if i == 0 {
+            text.push_str(&format!(
+                "\x1b_Ga=T,f=100,c={},r={},m={};{}\x1b\\",
+                columns, rows, more, chunk
+            ));
+        } else {
+            text.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    text.push('\n');
+    text
+}
+
+/// Encode the QR code as sixel graphics with a black and white palette. Every band of six pixel
+/// rows is drawn once per color, with runs of the same sixel compressed.
+fn sixel(matrix: &Matrix<Color>) -> String {
+    let size = matrix.size();
+    let width = size * BITMAP_MODULE_SIZE;
+    let pixels = matrix.pixels();
+    let is_dark = |x: usize, y: usize| pixels[(y / BITMAP_MODULE_SIZE) * size + x / BITMAP_MODULE_SIZE] == QrDark;
+    let mut text = format!("\x1bPq\"1;1;{};{}#0;2;0;0;0#1;2;100;100;100", width, width);
+    for band in (0..width).step_by(6) {
+        for (index, dark) in [(0, true), (1, false)] {
+            text.push_str(&format!("#{}", index));
+            let mut run: Option<(char, usize)> = None;
+            for x in 0..width {
+                let bits = (0..6)
+                    .filter(|bit| band + bit < width && is_dark(x, band + bit) == dark)
+                    .fold(0u8, |bits, bit| bits | 1 << bit);
+                let c = char::from(63 + bits);
+                run = match run {
+                    Some((previous, count)) if previous == c => Some((c, count + 1)),
+                    Some((previous, count)) => {
+                        push_sixel_run(&mut text, previous, count);
+                        Some((c, 1))
+                    }
+                    None => Some((c, 1)),
+                };
+            }
+            if let Some((c, count)) = run {
+                push_sixel_run(&mut text, c, count);
+            }
+            text.push('$');
+        }
+        text.push('-');
+    }
+    text.push_str("\x1b\\\n");
+    text
+}
+
+/// Append a run of the same sixel, compressed when longer than three.
+fn push_sixel_run(text: &mut String, c: char, count: usize) {
+    if count > 3 {
+        text.push_str(&format!("!{}{}", count, c));
+    } else {
+        text.extend(std::iter::repeat_n(c, count));
+    }
+}
+
+/// Size of the terminal in columns and rows, if stdout is a terminal.
+fn terminal_size() -> Option<(usize, usize)> {
+    if !io::stdout().is_tty() {
+        return None;
+    }
+    crossterm::terminal::size()
+        .ok()
+        .map(|(columns, rows)| (columns as usize, rows as usize))
+}
+
+/// Print the QR code in the terminal. When it does not fit the terminal, only the URL is printed,
+/// with a warning suggesting a more compact renderer or an image file.
+pub(crate) fn print_qr_code(matrix: Matrix<Color>, url: &str, render: QrRender, light_term: bool) -> QrSyncResult<()> {
+    let render = render.resolve();
+    tracing::debug!("Drawing the QR code with the {} renderer", render);
+    let qr = draw(matrix, render, light_term)?;
+    let mut stdout = io::stdout();
+    if let Some((columns, rows)) = terminal_size() {
+        if qr.columns > columns || qr.rows > rows {
+            let hint = match render {
+                QrRender::Compact => "enlarge it or save the QR code with --qr-png",
+                _ => "enlarge it, try --qr-render compact or save the QR code with --qr-png",
+            };
+            tracing::warn!(
+                "The QR code needs {}x{} characters but the terminal is {}x{}, {}",
+                qr.columns,
+                qr.rows,
+                columns,
+                rows,
+                hint
+            );
+            writeln!(stdout, "{}", url)?;
+            return Ok(());
+        }
+    }
+    stdout.write_all(qr.text.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use qr2term::qr::Qr;
+
+    fn matrix() -> Matrix<Color> {
+        Matrix::new(vec![QrDark, QrLight, QrLight, QrDark])
+    }
+
+    #[test]
+    fn test_draw_compact() {
+        let qr = draw(matrix(), QrRender::Compact, true).unwrap();
+        assert_eq!(qr.text, "   \n ▚ \n   \n");
+        assert_eq!((qr.columns, qr.rows), (3, 3));
+        let qr = draw(matrix(), QrRender::Compact, false).unwrap();
+        assert_eq!(qr.text, "███\n█▞█\n███\n");
+    }
+
+    #[test]
+    fn test_draw_compact_narrower_than_blocks() {
+        let matrix = || {
+            Qr::from("http://192.168.1.20:5566/receive?token=abc")
+                .unwrap()
+                .to_matrix()
+        };
+        let compact = draw(matrix(), QrRender::Compact, false).unwrap();
+        let blocks = draw(matrix(), QrRender::Blocks, false).unwrap();
+        assert_eq!(compact.rows, blocks.rows);
+        assert_eq!(compact.columns, blocks.columns.div_ceil(2));
+        assert_eq!(compact.text.lines().count(), compact.rows);
+        assert!(compact.text.lines().all(|line| line.chars().count() == compact.columns));
+    }
+
+    #[test]
+    fn test_draw_ascii() {
+        let qr = draw(matrix(), QrRender::Ascii, true).unwrap();
+        assert_eq!(
+            qr.text,
+            "            \n            \n    ##      \n      ##    \n            \n            \n"
+        );
+        assert_eq!((qr.columns, qr.rows), (12, 6));
+    }
+
+    #[test]
+    fn test_draw_blocks() {
+        let qr = draw(matrix(), QrRender::Blocks, false).unwrap();
+        assert_eq!((qr.columns, qr.rows), (6, 3));
+        assert_eq!(qr.text.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_draw_bitmaps() {
+        let qr = draw(matrix(), QrRender::Kitty, false).unwrap();
+        assert!(qr.text.starts_with("\x1b_Ga=T,f=100,c=6,r=3,m=0;iVBORw0KGgo"));
+        assert_eq!((qr.columns, qr.rows), (6, 3));
+        let qr = draw(matrix(), QrRender::Iterm, false).unwrap();
+        assert!(qr.text.starts_with("\x1b]1337;File=inline=1;size="));
+        assert!(qr.text.contains(";width=6;height=3;preserveAspectRatio=1:iVBORw0KGgo"));
+    }
+
+    /// Decode the grayscale pixels of the PNG drawn with the iTerm2 protocol, row by row.
+    fn iterm_pixels(text: &str) -> Vec<Vec<u8>> {
+        use std::io::Read;
+
+        let data = text.split(':').nth(1).unwrap().trim_end_matches("\x07\n");
+        let png = general_purpose::STANDARD.decode(data).unwrap();
+        let width = u32::from_be_bytes(png[16..20].try_into().unwrap()) as usize;
+        // Single IDAT chunk right after the IHDR chunk.
+        let length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut pixels = Vec::new();
+        flate2::read::ZlibDecoder::new(&png[41..41 + length])
+            .read_to_end(&mut pixels)
+            .unwrap();
+        // Each row starts with its filter type.
+        pixels.chunks(width + 1).map(|row| row[1..].to_vec()).collect()
+    }
+
+    /// Whether every pixel of the ring of modules between `from` and `to` from the border has the
+    /// given value.
+    fn ring_is(rows: &[Vec<u8>], from: usize, to: usize, value: u8) -> bool {
+        let width = rows.len();
+        let (from, to) = (from * BITMAP_MODULE_SIZE, to * BITMAP_MODULE_SIZE);
+        rows.iter().enumerate().all(|(y, row)| {
+            row.iter().enumerate().all(|(x, pixel)| {
+                let distance = x.min(y).min(width - 1 - x).min(width - 1 - y);
+                !(from..to).contains(&distance) || *pixel == value
+            })
+        })
+    }
+
+    #[test]
+    fn test_bitmap_light_term() {
+        // 2 modules, 2 modules of light quiet zone and 1 module of dark frame on each side.
+        let qr = draw(matrix(), QrRender::Iterm, true).unwrap();
+        assert_eq!((qr.columns, qr.rows), (8, 4));
+        let rows = iterm_pixels(&qr.text);
+        assert_eq!(rows.len(), 8 * BITMAP_MODULE_SIZE);
+        assert!(ring_is(&rows, 0, BITMAP_FRAME, 0x00));
+        assert!(ring_is(&rows, BITMAP_FRAME, BITMAP_FRAME + BLOCKS_QUIET_ZONE, 0xff));
+        let corner = (BITMAP_FRAME + BLOCKS_QUIET_ZONE) * BITMAP_MODULE_SIZE;
+        assert_eq!(rows[corner][corner], 0x00);
+        let qr = draw(matrix(), QrRender::Sixel, true).unwrap();
+        assert_eq!(qr.text, sixel(&bitmap_matrix(matrix(), true)));
+    }
+
+    #[test]
+    fn test_bitmap_dark_term() {
+        let qr = draw(matrix(), QrRender::Iterm, false).unwrap();
+        assert_eq!((qr.columns, qr.rows), (6, 3));
+        let rows = iterm_pixels(&qr.text);
+        assert_eq!(rows.len(), 6 * BITMAP_MODULE_SIZE);
+        assert!(ring_is(&rows, 0, BLOCKS_QUIET_ZONE, 0xff));
+    }
+
+    #[test]
+    fn test_sixel() {
+        let sixel = sixel(&Matrix::new(vec![QrDark]));
+        // A single dark module is 8x8 pixels: a full band of 6 rows and a band of 2 rows.
+        assert_eq!(
+            sixel,
+            "\x1bPq\"1;1;8;8#0;2;0;0;0#1;2;100;100;100#0!8~$#1!8?$-#0!8B$#1!8?$-\x1b\\\n"
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(n, _)| *n == name).map(|(_, v)| v.to_string())
+        };
+        assert_eq!(detect(env(&[("TERM", "xterm-kitty")])), QrRender::Kitty);
+        assert_eq!(detect(env(&[("TERM_PROGRAM", "iTerm.app")])), QrRender::Iterm);
+        assert_eq!(detect(env(&[("TERM", "foot")])), QrRender::Sixel);
+        assert_eq!(detect(env(&[("TERM", "xterm-256color")])), QrRender::Blocks);
+        assert_eq!(
+            detect(env(&[("TERM", "xterm-kitty"), ("TMUX", "/tmp/tmux-1000/default")])),
+            QrRender::Blocks
+        );
+    }
+
+    #[test]
+    fn test_qr_render() {
+        assert_eq!("compact".parse(), Ok(QrRender::Compact));
+        assert_eq!(QrRender::Ascii.to_string(), "ascii");
+        assert!("braille".parse::<QrRender>().is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! HTTPS support with self-signed or user-provided certificates.
+
+use std::path::PathBuf;
+
+use axum_server::tls_rustls::RustlsConfig;
+use sha2::{Digest, Sha256};
+
+use crate::{QrSyncError, QrSyncResult};
+
+/// Source of the certificate used to serve HTTPS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsCertificate {
+    /// Ephemeral self-signed certificate generated at startup for the advertised address.
+    SelfSigned,
+    /// Certificate and private key loaded from PEM files.
+    Files {
+        /// PEM file containing the certificate chain.
+        cert: PathBuf,
+        /// PEM file containing the private key.
+        key: PathBuf,
+    },
+}
+
+impl TlsCertificate {
+    /// Generate or load the certificate, returning the rustls configuration and the SHA-256
+    /// fingerprint of the leaf certificate, which can be verified on the mobile device. Generated
+    /// certificates are valid for all the given hosts.
+    pub(crate) async fn load(&self, hosts: &[String]) -> QrSyncResult<(RustlsConfig, String)> {
+        let (cert_pem, key_pem) = match self {
+            TlsCertificate::SelfSigned => {
+                let cert = rcgen::generate_simple_self_signed(hosts.to_vec())
+                    .map_err(|e| QrSyncError::Tls(format!("Unable to generate certificate: {e}")))?;
+                let cert_pem = cert
+                    .serialize_pem()
+                    .map_err(|e| QrSyncError::Tls(format!("Unable to serialize certificate: {e}")))?;
+                (cert_pem.into_bytes(), cert.serialize_private_key_pem().into_bytes())
+            }
+            TlsCertificate::Files { cert, key } => (tokio::fs::read(cert).await?, tokio::fs::read(key).await?),
+        };
+        let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())?;
+        let leaf = certs
+            .first()
+            .ok_or_else(|| QrSyncError::Tls("No certificate found in PEM file".into()))?;
+        let fingerprint = fingerprint(leaf);
+        let config = RustlsConfig::from_pem(cert_pem, key_pem)
+            .await
+            .map_err(|e| QrSyncError::Tls(format!("Invalid certificate or key: {e}")))?;
+        Ok((config, fingerprint))
+    }
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate, formatted as colon separated hex bytes.
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint = fingerprint(b"");
+        assert_eq!(fingerprint.len(), 95);
+        assert!(fingerprint.starts_with("E3:B0:C4:42"));
+    }
+
+    #[tokio::test]
+    async fn test_load_self_signed() {
+        let hosts = vec!["10.0.0.1".to_string(), "fd00::1".to_string()];
+        let (_, fingerprint) = TlsCertificate::SelfSigned.load(&hosts).await.unwrap();
+        assert_eq!(fingerprint.len(), 95);
+    }
+}
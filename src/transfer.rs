@@ -0,0 +1,204 @@
+//! Tracking of completed transfers, used to stop the server once the expected ones are done.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::Bytes;
+use futures_util::stream::Stream;
+use tokio::sync::Notify;
+
+/// Counter of the transfers completed during a session. A transfer is either a download streamed
+/// until its end or an upload batch where every file has been stored or skipped. When a
+/// limit is set, reaching it wakes up [`Transfers::done`].
+#[derive(Debug, Default)]
+pub struct Transfers {
+    limit: Option<usize>,
+    completed: AtomicUsize,
+    done: Notify,
+}
+
+impl Transfers {
+    /// Create a new instance of Transfers, finishing after `limit` transfers if any.
+    pub fn new(limit: Option<usize>) -> Self {
+        Transfers {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Number of transfers completed so far.
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    /// Record a completed transfer, waking up [`Transfers::done`] once the limit is reached.
+    pub(crate) fn complete(&self) {
+        let completed = self.completed.fetch_add(1, Ordering::SeqCst) + 1;
+        tracing::debug!("Completed {} transfers", completed);
+        if self.limit.is_some_and(|limit| completed >= limit) {
+            tracing::info!("All the expected transfers are completed");
+            self.done.notify_one();
+        }
+    }
+
+    /// Wait until the limit of transfers is reached. Never resolves if no limit is set.
+    pub async fn done(&self) {
+        match self.limit {
+            Some(limit) if self.completed() >= limit => (),
+            Some(_) => self.done.notified().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Wrap the body of a download, recording a transfer once it has been streamed entirely. The
+    /// transfer is recorded when the stream reports its end or, when the size is known, when the
+    /// body is dropped after all its bytes have been produced, since the HTTP server stops polling
+    /// a body once its content length has been reached. Empty files are counted this way too.
+    /// Downloads interrupted by the client or by an error are not counted.
+    pub(crate) fn track<S>(self: &Arc<Self>, stream: S, size: Option<u64>) -> TrackedStream<S>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
+        TrackedStream {
+            stream: Box::pin(stream),
+            transfers: self.clone(),
+            remaining: size,
+            finished: false,
+        }
+    }
+}
+
+/// Download body recording a transfer when its end is reached without errors.
+pub(crate) struct TrackedStream<S> {
+    stream: Pin<Box<S>>,
+    transfers: Arc<Transfers>,
+    remaining: Option<u64>,
+    finished: bool,
+}
+
+impl<S> TrackedStream<S> {
+    /// Record the transfer, only once and only if the download has not failed before.
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.transfers.complete();
+        }
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>>> Stream for TrackedStream<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.stream.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(remaining) = self.remaining {
+                    self.remaining = Some(remaining.saturating_sub(chunk.len() as u64));
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.finished = true,
+            Poll::Ready(None) => self.finish(),
+            Poll::Pending => (),
+        }
+        poll
+    }
+}
+
+impl<S> Drop for TrackedStream<S> {
+    fn drop(&mut self) {
+        if self.remaining == Some(0) {
+            self.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::stream::{self, StreamExt};
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_track_complete_download() {
+        let transfers = Arc::new(Transfers::new(Some(1)));
+        let chunks = vec![Ok(Bytes::from("a")), Ok(Bytes::from("b"))];
+        let body: Vec<_> = transfers.track(stream::iter(chunks), None).collect().await;
+        assert_eq!(body.len(), 2);
+        assert_eq!(transfers.completed(), 1);
+        transfers.done().await;
+    }
+
+    #[tokio::test]
+    async fn test_track_download_with_size() {
+        let transfers = Arc::new(Transfers::new(None));
+        let chunks = vec![Ok(Bytes::from("a")), Ok(Bytes::from("b"))];
+        let mut tracked = transfers.track(stream::iter(chunks), Some(2));
+        tracked.next().await;
+        tracked.next().await;
+        assert_eq!(transfers.completed(), 0);
+        assert!(tracked.next().await.is_none());
+        assert_eq!(transfers.completed(), 1);
+        drop(tracked);
+        assert_eq!(transfers.completed(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_track_download_dropped_after_last_byte() {
+        let transfers = Arc::new(Transfers::new(Some(1)));
+        let chunks = vec![Ok(Bytes::from("a")), Ok(Bytes::from("b"))];
+        let mut tracked = transfers.track(stream::iter(chunks), Some(2));
+        tracked.next().await;
+        tracked.next().await;
+        drop(tracked);
+        assert_eq!(transfers.completed(), 1);
+        transfers.done().await;
+    }
+
+    #[tokio::test]
+    async fn test_track_empty_download() {
+        let transfers = Arc::new(Transfers::new(Some(1)));
+        drop(transfers.track(stream::empty::<io::Result<Bytes>>(), Some(0)));
+        assert_eq!(transfers.completed(), 1);
+        transfers.done().await;
+        let transfers = Arc::new(Transfers::new(None));
+        let mut tracked = transfers.track(stream::empty::<io::Result<Bytes>>(), Some(0));
+        assert!(tracked.next().await.is_none());
+        drop(tracked);
+        assert_eq!(transfers.completed(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_track_interrupted_download() {
+        let transfers = Arc::new(Transfers::new(Some(1)));
+        let chunks = vec![Ok(Bytes::from("a")), Ok(Bytes::from("b"))];
+        let mut tracked = transfers.track(stream::iter(chunks), Some(2));
+        tracked.next().await;
+        drop(tracked);
+        assert_eq!(transfers.completed(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_track_failed_download() {
+        let transfers = Arc::new(Transfers::new(None));
+        let chunks = vec![Ok(Bytes::from("a")), Err(io::Error::other("boom"))];
+        let _: Vec<_> = transfers.track(stream::iter(chunks), None).collect().await;
+        assert_eq!(transfers.completed(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_done_waits_for_limit() {
+        let transfers = Arc::new(Transfers::new(Some(2)));
+        let waiter = tokio::spawn({
+            let transfers = transfers.clone();
+            async move { transfers.done().await }
+        });
+        transfers.complete();
+        assert!(!waiter.is_finished());
+        transfers.complete();
+        waiter.await.unwrap();
+    }
+}